@@ -0,0 +1,61 @@
+// cargo bench
+// plain timings (no criterion offline), so compare runs on the same machine only
+
+use std::collections::{BTreeMap, VecDeque};
+use std::hint::black_box;
+use std::time::Instant;
+
+use sequential_id_generator::availables::Availables;
+use sequential_id_generator::expires::Expires;
+use sequential_id_generator::pool::Pool;
+use sequential_id_generator::time_provider::FixedTimeProvider;
+
+
+const IDS: usize = 1_000_000;
+const TIMEOUT: i64 = 3000;
+
+fn bench (name: &str, ops: usize, f: impl FnOnce()) {
+    let start = Instant::now();
+    f();
+    let elapsed = start.elapsed();
+    println!("{:<45} {:>8.1} ns/op", name, elapsed.as_nanos() as f64 / ops as f64);
+}
+
+fn main () {
+    let ids = (1..=IDS).collect::<Vec<usize>>();
+    // every id once, in the scattered order real heartbeats arrive in (7919 is coprime with IDS)
+    let shuffled = (0..IDS).map(|i| i * 7919 % IDS + 1).collect::<Vec<usize>>();
+
+    let mut map = BTreeMap::new();
+    bench("btreemap insert", IDS, || for &id in ids.iter() { map.insert(id, id as i64); });
+    bench("btreemap heartbeat", IDS, || for &id in ids.iter() { map.insert(id, black_box(id as i64 + TIMEOUT)); });
+    bench("btreemap heartbeat (shuffled)", IDS, || for (i, &id) in shuffled.iter().enumerate() { map.insert(id, black_box(i as i64 + TIMEOUT * 2)); });
+    bench("btreemap get", IDS, || for &id in ids.iter() { black_box(map.get(&id)); });
+    bench("btreemap remove", IDS, || for &id in ids.iter() { map.remove(&id); });
+
+    let mut expires = Expires::new();
+    expires.add_range(1..=IDS);
+    bench("expires insert", IDS, || for &id in ids.iter() { expires.insert(id, id as i64); });
+    bench("expires heartbeat (any expire)", IDS, || for &id in ids.iter() { expires.insert(id, black_box(id as i64 + TIMEOUT)); });
+    bench("expires heartbeat (now + timeout)", IDS, || for &id in ids.iter() { expires.renew(id, black_box(id as i64 + TIMEOUT * 2)); });
+    // with the stale entries left by the last pass trimmed as it goes
+    bench("expires heartbeat again (now + timeout)", IDS, || for &id in ids.iter() { expires.renew(id, black_box(id as i64 + TIMEOUT * 3)); });
+    bench("expires heartbeat (shuffled, any expire)", IDS, || for (i, &id) in shuffled.iter().enumerate() { expires.insert(id, black_box(i as i64 + TIMEOUT * 4)); });
+    bench("expires heartbeat (shuffled, now + timeout)", IDS, || for (i, &id) in shuffled.iter().enumerate() { expires.renew(id, black_box(i as i64 + TIMEOUT * 5)); });
+    bench("expires get", IDS, || for &id in ids.iter() { black_box(expires.get(&id)); });
+    bench("expires remove", IDS, || for &id in ids.iter() { expires.remove(&id); });
+
+    let time_provider = FixedTimeProvider::arc_new(0);
+    let time_provider_pool = time_provider.clone();
+    let mut pool = Pool::new(TIMEOUT, VecDeque::from(ids.clone()), &time_provider_pool);
+    bench("pool allocate", IDS, || while pool.allocate().is_some() {});
+    // all leased, none expired: what every /next pays before allocating
+    bench("pool clear_expired (none)", 1, || { black_box(pool.clear_expired()); });
+    FixedTimeProvider::arc_set(&time_provider, TIMEOUT);
+    bench("pool clear_expired (all), per id", IDS, || { black_box(pool.clear_expired()); });
+
+    let mut pool = Pool::new(TIMEOUT, Availables::bitset(1..=IDS), &time_provider_pool);
+    bench("bitset pool allocate", IDS, || while pool.allocate().is_some() {});
+    FixedTimeProvider::arc_add(&time_provider, TIMEOUT);
+    bench("bitset pool clear_expired (all), per id", IDS, || { black_box(pool.clear_expired()); });
+}
@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+use std::mem;
+
+use serde::Serialize;
+
+use crate::Lease;
+
+
+// too few leases and the "typical" age means nothing
+const MIN_LEASES_FOR_TYPICAL: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Anomaly {
+    // still heartbeating long after most leases have come and gone
+    LongLived { id: usize, owner: Option<String>, age_ms: i64, typical_age_ms: i64 },
+    // keeps letting leases expire rather than holding on to them
+    RepeatedExpiry { owner: String, expirations: usize },
+    // heartbeats keep coming from different places, which may mean two clients think they hold it
+    ChangingAddress { id: usize, owner: Option<String>, changes: usize },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnomalyThresholds {
+    // how many times the typical age a lease has to reach to be flagged
+    pub age_factor: i64,
+    pub expirations: usize,
+    pub address_changes: usize,
+}
+
+// owner -> leases it let expire, over the current and previous anomaly checks only,
+// so owners are not flagged forever for a bad hour long ago, and ones that stop letting leases expire are forgotten
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExpirationsByOwner {
+    previous: BTreeMap<String, usize>,
+    current: BTreeMap<String, usize>,
+}
+
+impl ExpirationsByOwner {
+    pub fn record (&mut self, owner: String) {
+        *self.current.entry(owner).or_default() += 1;
+    }
+
+    pub fn counts (&self) -> BTreeMap<String, usize> {
+        let mut counts = self.previous.clone();
+        for (owner, &count) in self.current.iter() {
+            *counts.entry(owner.clone()).or_default() += count;
+        }
+        counts
+    }
+
+    // after each check, dropping everything from the one before
+    pub fn rotate (&mut self) {
+        self.previous = mem::take(&mut self.current);
+    }
+}
+
+// the lower median, so a few outliers in a small pool can't drag it up
+fn median (mut values: Vec<i64>) -> Option<i64> {
+    values.sort_unstable();
+    values.get(values.len().saturating_sub(1) / 2).copied()
+}
+
+pub fn find_anomalies (
+    leases: &BTreeMap<usize, Lease>,
+    expirations_by_owner: &BTreeMap<String, usize>,
+    now: i64,
+    timeout: i64,
+    thresholds: &AnomalyThresholds,
+) -> Vec<Anomaly> {
+    let mut anomalies = vec![];
+
+    if leases.len() >= MIN_LEASES_FOR_TYPICAL {
+        let ages = leases.values().map(|lease| now - lease.allocated).collect::<Vec<_>>();
+        // a freshly started pool would otherwise flag anything a few heartbeats old
+        let typical_age_ms = median(ages).unwrap_or_default().max(timeout);
+        for (&id, lease) in leases.iter() {
+            let age_ms = now - lease.allocated;
+            if age_ms > typical_age_ms * thresholds.age_factor {
+                anomalies.push(Anomaly::LongLived { id, owner: lease.owner.clone(), age_ms, typical_age_ms });
+            }
+        }
+    }
+
+    for (&id, lease) in leases.iter() {
+        if lease.address_changes >= thresholds.address_changes {
+            anomalies.push(Anomaly::ChangingAddress { id, owner: lease.owner.clone(), changes: lease.address_changes });
+        }
+    }
+
+    for (owner, &expirations) in expirations_by_owner.iter() {
+        if expirations >= thresholds.expirations {
+            anomalies.push(Anomaly::RepeatedExpiry { owner: owner.clone(), expirations });
+        }
+    }
+
+    anomalies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLDS: AnomalyThresholds = AnomalyThresholds { age_factor: 10, expirations: 3, address_changes: 2 };
+
+    fn lease (allocated: i64) -> Lease {
+        Lease { allocated, renewed: allocated, ..Lease::default() }
+    }
+
+    #[test]
+    fn long_lived () {
+        let leases = vec![(1, lease(0)), (2, lease(90_000)), (3, lease(95_000)), (4, lease(99_000))]
+            .into_iter().collect::<BTreeMap<_, _>>();
+        let anomalies = find_anomalies(&leases, &BTreeMap::new(), 100_000, 2000, &THRESHOLDS);
+        assert_eq!(anomalies, vec![
+            Anomaly::LongLived { id: 1, owner: None, age_ms: 100_000, typical_age_ms: 5000 },
+        ]);
+
+        // but not against a typical age shorter than the timeout
+        let anomalies = find_anomalies(&leases, &BTreeMap::new(), 100_000, 20_000, &THRESHOLDS);
+        assert_eq!(anomalies, vec![]);
+    }
+
+    #[test]
+    fn changing_address () {
+        let leases = vec![
+            (1, Lease { address_changes: 1, ..lease(0) }),
+            (2, Lease { address_changes: 2, owner: Some("a".to_string()), ..lease(0) }),
+        ].into_iter().collect::<BTreeMap<_, _>>();
+        let anomalies = find_anomalies(&leases, &BTreeMap::new(), 0, 2000, &THRESHOLDS);
+        assert_eq!(anomalies, vec![
+            Anomaly::ChangingAddress { id: 2, owner: Some("a".to_string()), changes: 2 },
+        ]);
+    }
+
+    #[test]
+    fn expirations_by_owner_window () {
+        let mut expirations = ExpirationsByOwner::default();
+        expirations.record("a".to_string());
+        expirations.rotate();
+        expirations.record("a".to_string());
+        expirations.record("b".to_string());
+        assert_eq!(expirations.counts(), vec![("a".to_string(), 2), ("b".to_string(), 1)].into_iter().collect());
+        expirations.rotate();
+        assert_eq!(expirations.counts(), vec![("a".to_string(), 1), ("b".to_string(), 1)].into_iter().collect());
+        expirations.rotate();
+        assert!(expirations.counts().is_empty());
+    }
+
+    #[test]
+    fn repeated_expiry () {
+        let expirations_by_owner = vec![("a".to_string(), 2), ("b".to_string(), 3)]
+            .into_iter().collect::<BTreeMap<_, _>>();
+        let anomalies = find_anomalies(&BTreeMap::new(), &expirations_by_owner, 0, 2000, &THRESHOLDS);
+        assert_eq!(anomalies, vec![
+            Anomaly::RepeatedExpiry { owner: "b".to_string(), expirations: 3 },
+        ]);
+    }
+}
@@ -0,0 +1,369 @@
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Serialize, Serializer};
+
+use crate::{json_error, ERROR_CODE_UNAUTHORIZED, ERROR_CODE_FORBIDDEN};
+use crate::requester::client_addr;
+
+
+// each role can do everything the ones before it can
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Reader,
+    Allocator,
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reader" => Ok(Role::Reader),
+            "allocator" => Ok(Role::Allocator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("Unknown role '{}'", s))
+        }
+    }
+}
+
+fn redacted<S: Serializer> (_: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str("<redacted>")
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ApiKey {
+    pub principal: String,
+    pub role: Role,
+    #[serde(serialize_with = "redacted")]
+    pub key: String,
+}
+
+impl FromStr for ApiKey {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(3, ':');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(principal), Some(role), Some(key)) if !principal.is_empty() && !key.is_empty() => Ok(ApiKey {
+                principal: principal.to_string(),
+                role: role.parse()?,
+                key: key.to_string(),
+            }),
+            // without the key itself, which should never end up in a log
+            _ => Err(format!("Expected principal:role:key, got '{}'", s.split(':').take(2).collect::<Vec<_>>().join(":")))
+        }
+    }
+}
+
+// eg "dashboard:reader:secret1,deployer:allocator:secret2"
+// a principal can have several keys at once, so they can be rotated without downtime
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct ApiKeys(pub Vec<ApiKey>);
+
+pub type SharedApiKeys = Arc<RwLock<ApiKeys>>;
+
+impl FromStr for ApiKeys {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Self, Self::Err> {
+        Self::from_entries(s.split(','))
+    }
+}
+
+// so how long a comparison takes says nothing about how much of a key was right
+fn constant_time_eq (a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl ApiKeys {
+    fn from_entries<'a> (entries: impl Iterator<Item = &'a str>) -> Result<Self, String> {
+        let api_keys = entries
+            .filter(|entry| !entry.trim().is_empty())
+            .map(ApiKey::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .map(Self)?;
+        api_keys.validate()?;
+        Ok(api_keys)
+    }
+
+    // one principal:role:key per line, with blank lines and # comments ignored
+    pub fn from_file_contents (s: &str) -> Result<Self, String> {
+        Self::from_entries(s.lines().filter(|line| !line.trim_start().starts_with('#')))
+    }
+
+    // the env keys plus whatever is currently in the file
+    pub fn load (env_keys: &ApiKeys, file: Option<&str>) -> Result<Self, String> {
+        let mut api_keys = env_keys.clone();
+        if let Some(file) = file {
+            let contents = fs::read_to_string(file).map_err(|e| format!("Reading {}: {}", file, e))?;
+            api_keys.0.extend(Self::from_file_contents(&contents)?.0);
+            api_keys.validate()?;
+        }
+        Ok(api_keys)
+    }
+
+    // rotating keys is fine, but a principal's role has to be unambiguous
+    fn validate (&self) -> Result<(), String> {
+        for api_key in self.0.iter() {
+            if self.0.iter().any(|other| other.principal == api_key.principal && other.role != api_key.role) {
+                return Err(format!("Principal '{}' has keys with different roles", api_key.principal));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_enabled (&self) -> bool {
+        !self.0.is_empty()
+    }
+
+    pub fn find (&self, key: &str) -> Option<&ApiKey> {
+        self.0.iter().find(|api_key| constant_time_eq(api_key.key.as_bytes(), key.as_bytes()))
+    }
+}
+
+// who is making an authenticated request, available to handlers as an extension
+#[derive(Debug, Clone, PartialEq)]
+pub struct Principal {
+    pub name: String,
+    pub role: Role,
+}
+
+fn presented_key (headers: &HeaderMap) -> Option<&str> {
+    if let Some(key) = headers.get("x-api-key").and_then(|value| value.to_str().ok()) {
+        return Some(key);
+    }
+    headers.get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+// with no keys configured, no key is ever right, rather than every request getting in
+pub fn authorize (headers: &HeaderMap, api_keys: &ApiKeys, role: Role) -> Result<Option<Principal>, usize> {
+    let api_key = presented_key(headers)
+        .and_then(|key| api_keys.find(key))
+        .ok_or(ERROR_CODE_UNAUTHORIZED)?;
+    if api_key.role < role {
+        return Err(ERROR_CODE_FORBIDDEN);
+    }
+    Ok(Some(Principal { name: api_key.principal.clone(), role: api_key.role }))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mechanism {
+    Open,
+    Localhost,
+    ApiKey,
+}
+
+impl FromStr for Mechanism {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(Mechanism::Open),
+            "localhost" => Ok(Mechanism::Localhost),
+            "api_key" => Ok(Mechanism::ApiKey),
+            _ => Err(format!("Unknown auth mechanism '{}'", s))
+        }
+    }
+}
+
+impl fmt::Display for Mechanism {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Mechanism::Open => "open",
+            Mechanism::Localhost => "localhost",
+            Mechanism::ApiKey => "api_key",
+        })
+    }
+}
+
+// alternatives separated by |, each needing all of its mechanisms joined by +, eg "localhost|api_key"
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthPolicy(pub Vec<Vec<Mechanism>>);
+
+impl Default for AuthPolicy {
+    fn default () -> Self {
+        Self(vec![vec![Mechanism::ApiKey]])
+    }
+}
+
+impl AuthPolicy {
+    pub fn uses (&self, mechanism: Mechanism) -> bool {
+        self.0.iter().flatten().any(|&other| other == mechanism)
+    }
+}
+
+impl FromStr for AuthPolicy {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Self, Self::Err> {
+        let policy = s.split('|')
+            .map(|alternative| alternative.split('+')
+                .map(|mechanism| mechanism.trim().parse())
+                .collect::<Result<Vec<_>, _>>())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self(policy))
+    }
+}
+
+impl fmt::Display for AuthPolicy {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let alternatives = self.0.iter()
+            .map(|mechanisms| mechanisms.iter().map(|mechanism| mechanism.to_string()).collect::<Vec<_>>().join("+"))
+            .collect::<Vec<_>>();
+        f.write_str(&alternatives.join("|"))
+    }
+}
+
+impl Serialize for AuthPolicy {
+    fn serialize<S: Serializer> (&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+fn check_mechanism (mechanism: Mechanism, client: IpAddr, headers: &HeaderMap, api_keys: &ApiKeys, role: Role) -> Result<Option<Principal>, usize> {
+    match mechanism {
+        Mechanism::Open => Ok(None),
+        Mechanism::Localhost => if client.is_loopback() { Ok(None) } else { Err(ERROR_CODE_FORBIDDEN) },
+        Mechanism::ApiKey => authorize(headers, api_keys, role),
+    }
+}
+
+pub fn check_policy (policy: &AuthPolicy, client: IpAddr, headers: &HeaderMap, api_keys: &ApiKeys, role: Role) -> Result<Option<Principal>, usize> {
+    let mut error = ERROR_CODE_FORBIDDEN;
+    for mechanisms in policy.0.iter() {
+        let checked = mechanisms.iter()
+            .map(|&mechanism| check_mechanism(mechanism, client, headers, api_keys, role))
+            .collect::<Result<Vec<_>, _>>();
+        match checked {
+            Ok(principals) => return Ok(principals.into_iter().flatten().next()),
+            // a missing key is the more useful thing to tell the client about
+            Err(code) if code == ERROR_CODE_UNAUTHORIZED => error = code,
+            Err(_) => {}
+        }
+    }
+    Err(error)
+}
+
+// what one route group's auth layer enforces
+#[derive(Debug, Clone)]
+pub struct AuthLayer {
+    pub policy: AuthPolicy,
+    pub role: Role,
+    pub api_keys: SharedApiKeys,
+    pub trusted_proxies: Arc<Vec<IpAddr>>,
+}
+
+pub async fn require_auth<B> (State(layer): State<AuthLayer>, mut request: Request<B>, next: Next<B>) -> Response {
+    let client = match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => client_addr(addr.ip(), request.headers(), &layer.trusted_proxies),
+        None => return (StatusCode::FORBIDDEN, json_error(ERROR_CODE_FORBIDDEN)).into_response(),
+    };
+    let checked = {
+        let api_keys = layer.api_keys.read().expect("Poisoned api keys lock");
+        check_policy(&layer.policy, client, request.headers(), &api_keys, layer.role)
+    };
+    match checked {
+        Ok(principal) => {
+            if let Some(principal) = principal {
+                request.extensions_mut().insert(principal);
+            }
+            next.run(request).await
+        }
+        Err(code) => {
+            let status = if code == ERROR_CODE_FORBIDDEN { StatusCode::FORBIDDEN } else { StatusCode::UNAUTHORIZED };
+            (status, json_error(code)).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers (name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn parse () {
+        let api_keys: ApiKeys = "dashboard:reader:abc, deployer:allocator:d:ef".parse().unwrap();
+        assert_eq!(api_keys.0[1], ApiKey { principal: "deployer".to_string(), role: Role::Allocator, key: "d:ef".to_string() });
+        assert!("dashboard:root:abc".parse::<ApiKeys>().is_err());
+        assert!("dashboard:reader".parse::<ApiKeys>().is_err());
+        assert_eq!(serde_json::to_string(&api_keys.0[0]).unwrap(), r#"{"principal":"dashboard","role":"reader","key":"<redacted>"}"#);
+    }
+
+    #[test]
+    fn parse_rotation () {
+        let api_keys: ApiKeys = "deployer:allocator:old,deployer:allocator:new".parse().unwrap();
+        assert_eq!(api_keys.find("old").map(|k| &k.principal), api_keys.find("new").map(|k| &k.principal));
+        assert!("deployer:allocator:old,deployer:admin:new".parse::<ApiKeys>().is_err());
+
+        let api_keys = ApiKeys::from_file_contents("# rotated 2023-11-12\n\ndashboard:reader:abc\n  deployer:allocator:xyz\n").unwrap();
+        assert_eq!(api_keys.0.len(), 2);
+    }
+
+    #[test]
+    fn authorize_roles () {
+        let api_keys: ApiKeys = "dashboard:reader:abc,ops:admin:xyz".parse().unwrap();
+        let dashboard = Principal { name: "dashboard".to_string(), role: Role::Reader };
+        assert_eq!(authorize(&headers("x-api-key", "abc"), &api_keys, Role::Reader), Ok(Some(dashboard)));
+        assert_eq!(authorize(&headers("x-api-key", "abc"), &api_keys, Role::Allocator), Err(ERROR_CODE_FORBIDDEN));
+        assert_eq!(authorize(&headers("authorization", "Bearer xyz"), &api_keys, Role::Allocator).map(|p| p.map(|p| p.role)), Ok(Some(Role::Admin)));
+        assert_eq!(authorize(&headers("x-api-key", "nope"), &api_keys, Role::Reader), Err(ERROR_CODE_UNAUTHORIZED));
+        assert_eq!(authorize(&HeaderMap::new(), &api_keys, Role::Reader), Err(ERROR_CODE_UNAUTHORIZED));
+        // and with no keys configured, nothing is
+        assert_eq!(authorize(&HeaderMap::new(), &ApiKeys::default(), Role::Admin), Err(ERROR_CODE_UNAUTHORIZED));
+        assert_eq!(authorize(&headers("x-api-key", ""), &ApiKeys::default(), Role::Reader), Err(ERROR_CODE_UNAUTHORIZED));
+    }
+
+    #[test]
+    fn parse_policy () {
+        let policy: AuthPolicy = "localhost | localhost+api_key".parse().unwrap();
+        assert_eq!(policy, AuthPolicy(vec![vec![Mechanism::Localhost], vec![Mechanism::Localhost, Mechanism::ApiKey]]));
+        assert_eq!(policy.to_string(), "localhost|localhost+api_key");
+        assert!("mtls".parse::<AuthPolicy>().is_err());
+    }
+
+    #[test]
+    fn check_policy_alternatives () {
+        let api_keys: ApiKeys = "ops:admin:xyz".parse().unwrap();
+        let local = "127.0.0.1".parse().unwrap();
+        let remote = "1.1.1.1".parse().unwrap();
+        let ops = Some(Principal { name: "ops".to_string(), role: Role::Admin });
+
+        let policy: AuthPolicy = "localhost|api_key".parse().unwrap();
+        assert_eq!(check_policy(&policy, local, &HeaderMap::new(), &api_keys, Role::Allocator), Ok(None));
+        assert_eq!(check_policy(&policy, remote, &HeaderMap::new(), &api_keys, Role::Allocator), Err(ERROR_CODE_UNAUTHORIZED));
+        assert_eq!(check_policy(&policy, remote, &headers("x-api-key", "xyz"), &api_keys, Role::Allocator), Ok(ops.clone()));
+
+        let policy: AuthPolicy = "localhost+api_key".parse().unwrap();
+        assert_eq!(check_policy(&policy, remote, &headers("x-api-key", "xyz"), &api_keys, Role::Allocator), Err(ERROR_CODE_FORBIDDEN));
+        assert_eq!(check_policy(&policy, local, &headers("x-api-key", "xyz"), &api_keys, Role::Allocator), Ok(ops));
+
+        let policy: AuthPolicy = "open".parse().unwrap();
+        assert_eq!(check_policy(&policy, remote, &HeaderMap::new(), &api_keys, Role::Admin), Ok(None));
+
+        // without any keys, only the localhost alternative is left
+        let policy: AuthPolicy = "localhost|api_key".parse().unwrap();
+        assert_eq!(check_policy(&policy, remote, &HeaderMap::new(), &ApiKeys::default(), Role::Admin), Err(ERROR_CODE_UNAUTHORIZED));
+        assert_eq!(check_policy(&policy, local, &HeaderMap::new(), &ApiKeys::default(), Role::Admin), Ok(None));
+    }
+}
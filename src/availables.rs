@@ -0,0 +1,292 @@
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+use crate::memory::vec_bytes;
+
+
+const WORD_BITS: usize = u64::BITS as usize;
+
+// one bit per id in a fixed range, handing them out in ascending order from a cursor that wraps around,
+// so a just released id is only reused once every id after it has been tried
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bitset {
+    // the id of bit 0
+    base: usize,
+    words: Vec<u64>,
+    // bit index the next scan starts from
+    cursor: usize,
+    len: usize,
+}
+
+impl Bitset {
+    // all available
+    pub fn from_range (range: RangeInclusive<usize>) -> Self {
+        let mut bitset = Self {
+            base: *range.start(),
+            words: vec![],
+            cursor: 0,
+            len: 0,
+        };
+        for id in range {
+            bitset.insert(id);
+        }
+        bitset
+    }
+
+    fn contains (&self, index: usize) -> bool {
+        self.words.get(index / WORD_BITS).is_some_and(|word| word & (1 << (index % WORD_BITS)) != 0)
+    }
+
+    // whether it was newly set
+    fn insert (&mut self, id: usize) -> bool {
+        let index = id.checked_sub(self.base).expect("Id below the bitset range");
+        if index / WORD_BITS >= self.words.len() {
+            self.words.resize(index / WORD_BITS + 1, 0);
+        }
+        let was_set = self.contains(index);
+        self.words[index / WORD_BITS] |= 1 << (index % WORD_BITS);
+        if !was_set {
+            self.len += 1;
+        }
+        !was_set
+    }
+
+    fn remove (&mut self, id: usize) -> bool {
+        match id.checked_sub(self.base) {
+            Some(index) if self.contains(index) => {
+                self.words[index / WORD_BITS] &= !(1 << (index % WORD_BITS));
+                self.len -= 1;
+                true
+            }
+            _ => false
+        }
+    }
+
+    // the first set bit index in from..to
+    fn scan (&self, from: usize, to: usize) -> Option<usize> {
+        let mut index = from;
+        while index < to {
+            let word = self.words[index / WORD_BITS] >> (index % WORD_BITS);
+            if word == 0 {
+                index = (index / WORD_BITS + 1) * WORD_BITS;
+            } else {
+                index += word.trailing_zeros() as usize;
+                return (index < to).then_some(index);
+            }
+        }
+        None
+    }
+
+    fn pop_front (&mut self) -> Option<usize> {
+        let end = self.words.len() * WORD_BITS;
+        let index = self.scan(self.cursor, end)
+            .or_else(|| self.scan(0, self.cursor))?;
+        let id = self.base + index;
+        self.remove(id);
+        self.cursor = index + 1;
+        Some(id)
+    }
+
+    // the first available id within any of the ranges, from the cursor on like pop_front,
+    // only ever scanning the words those ranges cover
+    fn take_in (&mut self, ranges: &[RangeInclusive<usize>]) -> Option<usize> {
+        let end = self.words.len() * WORD_BITS;
+        let index = ranges.iter().filter_map(|range| {
+            let from = range.start().saturating_sub(self.base);
+            let to = range.end().checked_sub(self.base)?.saturating_add(1).min(end);
+            if from >= to {
+                return None;
+            }
+            let cursor = self.cursor.clamp(from, to);
+            self.scan(cursor, to).or_else(|| self.scan(from, cursor))
+        })
+            // whichever would be handed out first
+            .min_by_key(|&index| (index < self.cursor, index))?;
+        let id = self.base + index;
+        self.remove(id);
+        Some(id)
+    }
+
+    // in the order they would be handed out
+    fn iter (&self) -> impl Iterator<Item = usize> + '_ {
+        let end = self.words.len() * WORD_BITS;
+        (self.cursor..end).chain(0..self.cursor.min(end))
+            .filter(|&index| self.contains(index))
+            .map(|index| self.base + index)
+    }
+}
+
+// the ids not currently leased, in the order they will be handed out
+#[derive(Debug, Clone, PartialEq)]
+pub enum Availables {
+    // first in first out, so the least recently released id goes next (8 bytes per id)
+    Queue(VecDeque<usize>),
+    // for very large ranges (1 bit per id)
+    Bitset(Bitset),
+}
+
+impl Default for Availables {
+    fn default () -> Self {
+        Availables::Queue(VecDeque::new())
+    }
+}
+
+impl From<VecDeque<usize>> for Availables {
+    fn from (queue: VecDeque<usize>) -> Self {
+        Availables::Queue(queue)
+    }
+}
+
+impl Availables {
+    pub fn queue (range: RangeInclusive<usize>) -> Self {
+        Availables::Queue(range.collect())
+    }
+
+    pub fn bitset (range: RangeInclusive<usize>) -> Self {
+        Availables::Bitset(Bitset::from_range(range))
+    }
+
+    // the same representation, for another range
+    pub fn like (&self, range: RangeInclusive<usize>) -> Self {
+        match self {
+            Availables::Queue(_) => Self::queue(range),
+            Availables::Bitset(_) => Self::bitset(range),
+        }
+    }
+
+    // the lowest to the highest id, if any
+    pub fn span (&self) -> Option<RangeInclusive<usize>> {
+        match self {
+            Availables::Queue(queue) => Some(*queue.iter().min()?..=*queue.iter().max()?),
+            Availables::Bitset(bitset) => (!bitset.words.is_empty())
+                .then(|| bitset.base..=bitset.base + bitset.words.len() * WORD_BITS - 1),
+        }
+    }
+
+    pub fn len (&self) -> usize {
+        match self {
+            Availables::Queue(queue) => queue.len(),
+            Availables::Bitset(bitset) => bitset.len,
+        }
+    }
+
+    pub fn is_empty (&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn memory_bytes (&self) -> usize {
+        match self {
+            Availables::Queue(queue) => vec_bytes::<usize>(queue.capacity()),
+            Availables::Bitset(bitset) => vec_bytes::<u64>(bitset.words.capacity()),
+        }
+    }
+
+    pub fn pop_front (&mut self) -> Option<usize> {
+        match self {
+            Availables::Queue(queue) => queue.pop_front(),
+            Availables::Bitset(bitset) => bitset.pop_front(),
+        }
+    }
+
+    pub fn push_back (&mut self, id: usize) {
+        match self {
+            Availables::Queue(queue) => queue.push_back(id),
+            Availables::Bitset(bitset) => {
+                bitset.insert(id);
+            }
+        }
+    }
+
+    // a specific id, if it is available
+    pub fn remove (&mut self, id: usize) -> Option<usize> {
+        match self {
+            Availables::Queue(queue) => {
+                let index = queue.iter().position(|&available| available == id)?;
+                queue.remove(index)
+            }
+            Availables::Bitset(bitset) => bitset.remove(id).then_some(id),
+        }
+    }
+
+    // the next one within any of the ranges, eg for a label
+    pub fn take_in (&mut self, ranges: &[RangeInclusive<usize>]) -> Option<usize> {
+        match self {
+            Availables::Queue(queue) => {
+                // walks past every id outside the ranges, so O(n) under the pool lock for a large queue,
+                // where a bitset only scans the ranges themselves
+                let index = queue.iter().position(|id| ranges.iter().any(|range| range.contains(id)))?;
+                queue.remove(index)
+            }
+            Availables::Bitset(bitset) => bitset.take_in(ranges),
+        }
+    }
+
+    pub fn iter (&self) -> impl Iterator<Item = usize> + '_ {
+        let (queue, bitset) = match self {
+            Availables::Queue(queue) => (Some(queue.iter().copied()), None),
+            Availables::Bitset(bitset) => (None, Some(bitset.iter())),
+        };
+        queue.into_iter().flatten().chain(bitset.into_iter().flatten())
+    }
+}
+
+impl PartialEq<VecDeque<usize>> for Availables {
+    fn eq (&self, other: &VecDeque<usize>) -> bool {
+        self.iter().eq(other.iter().copied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitset_cursor_order () {
+        let mut availables = Availables::bitset(10..=140);
+        assert_eq!(availables.len(), 131);
+        assert_eq!(availables.pop_front(), Some(10));
+        assert_eq!(availables.pop_front(), Some(11));
+        assert_eq!(availables.remove(12), Some(12));
+        assert_eq!(availables.remove(12), None);
+        assert_eq!(availables.remove(5), None);
+        assert_eq!(availables.pop_front(), Some(13));
+        // released behind the cursor, so not reused until it wraps around
+        availables.push_back(10);
+        assert_eq!(availables.iter().next(), Some(14));
+        assert_eq!(availables.iter().last(), Some(10));
+        while availables.len() > 1 {
+            availables.pop_front();
+        }
+        assert_eq!(availables, VecDeque::from(vec![10]));
+        assert_eq!(availables.pop_front(), Some(10));
+        assert_eq!(availables.pop_front(), None);
+        assert!(Availables::bitset(1..=1_000_000).memory_bytes() * 32 < Availables::queue(1..=1_000_000).memory_bytes());
+    }
+
+    #[test]
+    fn take_in_ranges () {
+        for mut availables in [Availables::bitset(10..=140), Availables::queue(10..=140)] {
+            assert_eq!(availables.pop_front(), Some(10));
+            assert_eq!(availables.take_in(&[1..=5, 100..=101, 9..=11]), Some(11));
+            assert_eq!(availables.take_in(&[100..=101]), Some(100));
+            assert_eq!(availables.take_in(&[100..=101, 141..=usize::MAX]), Some(101));
+            assert_eq!(availables.take_in(&[100..=101, 141..=usize::MAX]), None);
+            // in the order they are handed out, not the order of the ranges
+            availables.push_back(100);
+            assert_eq!(availables.take_in(&[100..=100, 0..=usize::MAX]), Some(12));
+            assert_eq!(availables.len(), 127);
+        }
+    }
+
+    #[test]
+    fn queue_fifo () {
+        let mut availables = Availables::queue(1..=3);
+        assert_eq!(availables.pop_front(), Some(1));
+        availables.push_back(1);
+        assert_eq!(availables.remove(2), Some(2));
+        assert_eq!(availables, VecDeque::from(vec![3, 1]));
+        assert_eq!(availables.like(5..=6), VecDeque::from(vec![5, 6]));
+    }
+}
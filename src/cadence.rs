@@ -0,0 +1,89 @@
+use std::collections::VecDeque;
+
+use serde::Serialize;
+
+
+// recommending on fewer than this would mostly be recommending on noise
+pub const MIN_SAMPLES: usize = 20;
+// the timeout should survive this many heartbeats in a row going missing (eg a GC pause or a slow deploy)
+pub const MISSED_HEARTBEATS: i64 = 2;
+
+// the most recent intervals between accepted heartbeats for the same id, across all leases
+#[derive(Debug, Clone)]
+pub struct HeartbeatCadence {
+    capacity: usize,
+    intervals: VecDeque<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TimeoutRecommendation {
+    pub samples: usize,
+    pub interval_p50: i64,
+    pub interval_p99: i64,
+    pub recommended: i64,
+}
+
+// nearest rank, on already sorted values
+fn percentile (sorted: &[i64], p: usize) -> i64 {
+    let rank = (sorted.len() * p).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+impl HeartbeatCadence {
+    pub fn new (capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            intervals: VecDeque::new(),
+        }
+    }
+
+    pub fn record (&mut self, interval: i64) {
+        if self.intervals.len() >= self.capacity {
+            self.intervals.pop_front();
+        }
+        self.intervals.push_back(interval);
+    }
+
+    pub fn recommend (&self) -> Option<TimeoutRecommendation> {
+        if self.intervals.len() < MIN_SAMPLES {
+            return None;
+        }
+        let mut sorted = self.intervals.iter().copied().collect::<Vec<_>>();
+        sorted.sort_unstable();
+        let interval_p99 = percentile(&sorted, 99);
+        Some(TimeoutRecommendation {
+            samples: sorted.len(),
+            interval_p50: percentile(&sorted, 50),
+            interval_p99,
+            // a slow heartbeat, plus that many more missing, and it still hasn't expired
+            recommended: interval_p99 * (1 + MISSED_HEARTBEATS) + 1,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recommend_from_p99 () {
+        let mut cadence = HeartbeatCadence::new(100);
+        for _ in 0..MIN_SAMPLES - 1 {
+            cadence.record(1000);
+        }
+        assert_eq!(cadence.recommend(), None);
+        cadence.record(1500);
+        assert_eq!(cadence.recommend(), Some(TimeoutRecommendation {
+            samples: MIN_SAMPLES,
+            interval_p50: 1000,
+            interval_p99: 1500,
+            recommended: 4501,
+        }));
+
+        // only the most recent are kept
+        for _ in 0..100 {
+            cadence.record(200);
+        }
+        assert_eq!(cadence.recommend().map(|recommendation| recommendation.recommended), Some(601));
+    }
+}
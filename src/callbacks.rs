@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use hyper::{Body, Client, Method, Request, Uri};
+use hyper::client::HttpConnector;
+use serde_json::{Value, json};
+
+use crate::logging::{self, Severity};
+
+
+// long enough for a slow receiver, short enough that a dead one doesn't pile up tasks
+const CALLBACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// where lease notifications go, eg to the supervisor of a worker that may be hung,
+// and must not block, since it is called with the pool lock held
+pub trait CallbackSender: Send + Sync {
+    fn send (&self, url: &str, body: Value);
+}
+
+// only plain http, like the sentry reporter, so https receivers need a tls terminating proxy
+pub fn valid_url (url: &str) -> bool {
+    url.parse::<Uri>().is_ok_and(|uri| uri.scheme_str() == Some("http") && uri.host().is_some())
+}
+
+// one POST per notification, in the background and best effort (failures are only logged)
+#[derive(Debug, Clone, Default)]
+pub struct HttpCallbacks {
+    client: Client<HttpConnector>,
+}
+
+impl CallbackSender for HttpCallbacks {
+    fn send (&self, url: &str, body: Value) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()));
+        let (client, url) = (self.client.clone(), url.to_string());
+        runtime.spawn(async move {
+            let result = match request {
+                Ok(request) => match tokio::time::timeout(CALLBACK_TIMEOUT, client.request(request)).await {
+                    Ok(Ok(response)) if response.status().is_success() => Ok(()),
+                    Ok(Ok(response)) => Err(response.status().to_string()),
+                    Ok(Err(e)) => Err(e.to_string()),
+                    Err(_) => Err("timed out".to_string()),
+                },
+                Err(e) => Err(e.to_string()),
+            };
+            if let Err(e) = result {
+                logging::log(Severity::Warning, &json!({ "event": "callback_failed", "url": url, "error": e }));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls () {
+        assert!(valid_url("http://supervisor.internal:8080/leases?worker=7"));
+        assert!(!valid_url("https://supervisor.internal/leases"));
+        assert!(!valid_url("file:///etc/passwd"));
+        assert!(!valid_url("/leases"));
+        assert!(!valid_url("not a url"));
+    }
+}
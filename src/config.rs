@@ -0,0 +1,234 @@
+use std::env;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+
+use serde::Serialize;
+
+use crate::auth::{ApiKeys, AuthPolicy, Mechanism};
+use crate::labels::Labels;
+use crate::logging::LogConfig;
+use crate::maintenance::MaintenanceWindows;
+use crate::quota::Quotas;
+use crate::reporter::SentryDsn;
+use crate::waiters::{Discipline, Priorities};
+
+
+pub const DEFAULT_PORT: u16 = 3000;
+pub const DEFAULT_MAX: usize = 65535;
+pub const DEFAULT_MIN: usize = 1;
+pub const DEFAULT_TIMEOUT: i64 = 3000;
+pub const DEFAULT_MIN_HEARTBEAT_INTERVAL: i64 = 0;
+pub const DEFAULT_BACKOFF_BASE: i64 = 100;
+pub const DEFAULT_BACKOFF_MAX: i64 = 30000;
+pub const DEFAULT_STATS_HISTORY_HOURS: usize = 24;
+pub const DEFAULT_ANOMALY_INTERVAL: u64 = 60000;
+pub const DEFAULT_ANOMALY_AGE_FACTOR: i64 = 10;
+pub const DEFAULT_ANOMALY_EXPIRATIONS: usize = 3;
+pub const DEFAULT_ANOMALY_ADDRESS_CHANGES: usize = 2;
+pub const DEFAULT_API_KEYS_RELOAD_INTERVAL: u64 = 5000;
+pub const DEFAULT_EXPIRY_BATCH: usize = 1000;
+pub const DEFAULT_REAPER_INTERVAL: u64 = 1000;
+pub const DEFAULT_SLOW_OPERATION_THRESHOLD: u64 = 10;
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 0;
+pub const DEFAULT_HEARTBEAT_SAMPLES: usize = 1000;
+pub const DEFAULT_SYSLOG_ADDR: &str = "/dev/log";
+pub const DEFAULT_SELF_CHECK_INTERVAL: u64 = 60000;
+
+// everything read from the environment at startup, as reported by /config (so secrets must serialize redacted)
+#[derive(Debug, Clone, Serialize)]
+pub struct Config {
+    pub port: u16,
+    pub min: usize,
+    pub max: usize,
+    pub timeout: i64,
+    pub min_heartbeat_interval: i64,
+    pub backoff_base: i64,
+    pub backoff_max: i64,
+    pub stats_history_hours: usize,
+    pub anomaly_interval: u64,
+    pub anomaly_age_factor: i64,
+    pub anomaly_expirations: usize,
+    pub anomaly_address_changes: usize,
+    pub trusted_proxies: Vec<IpAddr>,
+    pub overflow: Option<RangeInclusive<usize>>,
+    // 1 bit per available id instead of 8 bytes, for ranges in the millions
+    pub bitset_availables: bool,
+    // per lock acquisition, with the reaper task catching up on the rest every reaper_interval
+    pub expiry_batch: usize,
+    pub reaper_interval: u64,
+    // ms holding the lock before an operation is logged as slow
+    pub slow_operation_threshold: u64,
+    // lease requests in flight before allocations are refused (0 never)
+    pub max_in_flight: usize,
+    // the line protocol listener, off unless set
+    pub tcp_port: Option<u16>,
+    // auto tuning the timeout to the heartbeat cadence, within these, when both are set
+    pub timeout_bounds: Option<RangeInclusive<i64>>,
+    pub log: LogConfig,
+    // periodically verifying the pool's invariants, with --self-check (eg for soak tests)
+    pub self_check: bool,
+    pub self_check_interval: u64,
+    // a clock that only moves through /debug/clock, with --test-clock (for client test suites, never production)
+    pub test_clock: bool,
+    // errors are also sent here, with the sentry feature
+    pub sentry_dsn: Option<SentryDsn>,
+    pub maintenance_windows: MaintenanceWindows,
+    pub api_keys: ApiKeys,
+    // re-read whenever it changes, on top of api_keys
+    pub api_keys_file: Option<String>,
+    pub api_keys_reload_interval: u64,
+    // ids already in use (eg by legacy processes) at startup
+    pub seed_file: Option<String>,
+    pub quotas: Quotas,
+    // capacity classes within the pool, for /next?label=
+    pub labels: Labels,
+    // whether /next takes a callback url, off by default since it lets clients make the server POST anywhere
+    pub callbacks: bool,
+    // which waiting ticket gets the next freed id
+    pub waiter_discipline: Discipline,
+    pub ticket_priorities: Priorities,
+    // per route group
+    pub auth_lease: AuthPolicy,
+    pub auth_read: AuthPolicy,
+    pub auth_admin: AuthPolicy,
+}
+
+fn env_var_parse<T: std::str::FromStr> (name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(s) => s.parse::<T>().unwrap_or(default),
+        _ => default
+    }
+}
+
+fn env_var_parse_opt<T: std::str::FromStr> (name: &str) -> Option<T> {
+    match env::var(name) {
+        Ok(s) => s.parse::<T>().ok(),
+        _ => None
+    }
+}
+
+// when unset, api keys once there are any, and otherwise the given policy
+fn env_var_policy (name: &str, keyless: &str, has_keys: bool) -> AuthPolicy {
+    let policy = match env::var(name) {
+        Ok(s) => s.parse().unwrap_or_else(|e| panic!("Invalid {}: {}", name, e)),
+        _ if has_keys => AuthPolicy::default(),
+        _ => keyless.parse().expect("Invalid keyless policy"),
+    };
+    if policy.uses(Mechanism::ApiKey) && !has_keys {
+        // it could never pass, which is surely not what was meant
+        panic!("Invalid {}: api_key needs API_KEYS or API_KEYS_FILE", name);
+    }
+    policy
+}
+
+impl Config {
+    pub fn from_env () -> Self {
+        let max = env_var_parse("MAX", DEFAULT_MAX);
+        let min = env_var_parse("MIN", DEFAULT_MIN);
+
+        let overflow_max: Option<usize> = env_var_parse_opt("OVERFLOW_MAX");
+        // right after the primary range by default, unless that already ends at the largest id there is
+        let overflow_min = env_var_parse_opt("OVERFLOW_MIN").or(max.checked_add(1));
+        let overflow = overflow_max.map(|overflow_max| {
+            let Some(overflow_min) = overflow_min else {
+                panic!("OVERFLOW_MAX needs OVERFLOW_MIN set too, since MAX {} leaves no room after it", max);
+            };
+            if overflow_min <= max && overflow_max >= min {
+                panic!("Overflow range {}..={} overlaps primary range {}..={}", overflow_min, overflow_max, min, max);
+            }
+            overflow_min..=overflow_max
+        });
+
+        let labels: Labels = env::var("LABELS").unwrap_or_default()
+            .parse()
+            .expect("Invalid LABELS");
+        for (label, ranges) in labels.0.iter() {
+            for range in ranges {
+                let within = |pool: &RangeInclusive<usize>| pool.start() <= range.start() && range.end() <= pool.end();
+                // it would quietly never hand out anything
+                if !within(&(min..=max)) && !overflow.as_ref().is_some_and(within) {
+                    panic!("Label {} range {}-{} is outside both primary range {}..={} and the overflow range", label, range.start(), range.end(), min, max);
+                }
+            }
+        }
+
+        let api_keys: ApiKeys = env::var("API_KEYS").unwrap_or_default()
+            .parse()
+            .expect("Invalid API_KEYS");
+        let api_keys_file = env::var("API_KEYS_FILE").ok();
+        let has_keys = api_keys.is_enabled() || api_keys_file.is_some();
+
+        Self {
+            port: env_var_parse("PORT", DEFAULT_PORT),
+            min,
+            max,
+            timeout: env_var_parse("TIMEOUT", DEFAULT_TIMEOUT),
+            min_heartbeat_interval: env_var_parse("MIN_HEARTBEAT_INTERVAL", DEFAULT_MIN_HEARTBEAT_INTERVAL),
+            backoff_base: env_var_parse("BACKOFF_BASE", DEFAULT_BACKOFF_BASE),
+            backoff_max: env_var_parse("BACKOFF_MAX", DEFAULT_BACKOFF_MAX),
+            stats_history_hours: env_var_parse("STATS_HISTORY_HOURS", DEFAULT_STATS_HISTORY_HOURS),
+            anomaly_interval: env_var_parse("ANOMALY_INTERVAL", DEFAULT_ANOMALY_INTERVAL),
+            anomaly_age_factor: env_var_parse("ANOMALY_AGE_FACTOR", DEFAULT_ANOMALY_AGE_FACTOR),
+            anomaly_expirations: env_var_parse("ANOMALY_EXPIRATIONS", DEFAULT_ANOMALY_EXPIRATIONS),
+            anomaly_address_changes: env_var_parse("ANOMALY_ADDRESS_CHANGES", DEFAULT_ANOMALY_ADDRESS_CHANGES),
+            trusted_proxies: env::var("TRUSTED_PROXIES").unwrap_or_default()
+                .split(',')
+                .filter(|addr| !addr.trim().is_empty())
+                .map(|addr| addr.trim().parse::<IpAddr>().expect("Invalid TRUSTED_PROXIES"))
+                .collect(),
+            overflow,
+            bitset_availables: env_var_parse("BITSET_AVAILABLES", false),
+            expiry_batch: env_var_parse("EXPIRY_BATCH", DEFAULT_EXPIRY_BATCH).max(1),
+            reaper_interval: env_var_parse("REAPER_INTERVAL", DEFAULT_REAPER_INTERVAL),
+            slow_operation_threshold: env_var_parse("SLOW_OPERATION_THRESHOLD", DEFAULT_SLOW_OPERATION_THRESHOLD),
+            max_in_flight: env_var_parse("MAX_IN_FLIGHT", DEFAULT_MAX_IN_FLIGHT),
+            tcp_port: env_var_parse_opt("TCP_PORT"),
+            timeout_bounds: env_var_parse_opt("TIMEOUT_AUTO_MIN")
+                .zip(env_var_parse_opt("TIMEOUT_AUTO_MAX"))
+                .map(|(min, max)| {
+                    if min > max {
+                        panic!("TIMEOUT_AUTO_MIN {} is above TIMEOUT_AUTO_MAX {}", min, max);
+                    }
+                    min..=max
+                }),
+            self_check: env::args().any(|arg| arg == "--self-check") || env_var_parse("SELF_CHECK", false),
+            self_check_interval: env_var_parse("SELF_CHECK_INTERVAL", DEFAULT_SELF_CHECK_INTERVAL),
+            test_clock: env::args().any(|arg| arg == "--test-clock") || env_var_parse("TEST_CLOCK", false),
+            log: match env::var("LOG_TARGET").unwrap_or_default().as_str() {
+                "" | "stderr" => LogConfig::Stderr,
+                "syslog" => LogConfig::Syslog {
+                    addr: env::var("SYSLOG_ADDR").unwrap_or(DEFAULT_SYSLOG_ADDR.to_string()),
+                    facility: env::var("SYSLOG_FACILITY").unwrap_or("local0".to_string())
+                        .parse()
+                        .expect("Invalid SYSLOG_FACILITY"),
+                },
+                target => panic!("Invalid LOG_TARGET: {}", target),
+            },
+            sentry_dsn: env::var("SENTRY_DSN").ok()
+                .filter(|dsn| !dsn.trim().is_empty())
+                .map(|dsn| dsn.parse().expect("Invalid SENTRY_DSN")),
+            maintenance_windows: env::var("MAINTENANCE_WINDOWS").unwrap_or_default()
+                .parse()
+                .expect("Invalid MAINTENANCE_WINDOWS"),
+            api_keys,
+            api_keys_file,
+            seed_file: env::var("SEED_FILE").ok(),
+            api_keys_reload_interval: env_var_parse("API_KEYS_RELOAD_INTERVAL", DEFAULT_API_KEYS_RELOAD_INTERVAL),
+            quotas: env::var("QUOTAS").unwrap_or_default()
+                .parse()
+                .expect("Invalid QUOTAS"),
+            labels,
+            callbacks: env_var_parse("CALLBACKS", false),
+            waiter_discipline: env::var("WAITER_DISCIPLINE").unwrap_or("fifo".to_string())
+                .parse()
+                .expect("Invalid WAITER_DISCIPLINE"),
+            ticket_priorities: env::var("TICKET_PRIORITIES").unwrap_or_default()
+                .parse()
+                .expect("Invalid TICKET_PRIORITIES"),
+            auth_lease: env_var_policy("AUTH_LEASE", "open", has_keys),
+            auth_read: env_var_policy("AUTH_READ", "open", has_keys),
+            // pinning and moving the clock are never open by default
+            auth_admin: env_var_policy("AUTH_ADMIN", "localhost", has_keys),
+        }
+    }
+}
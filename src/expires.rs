@@ -0,0 +1,269 @@
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+use crate::memory::{btree_bytes, vec_bytes};
+
+
+// the ids of one range, indexed by offset from its start, and only grown as far as the highest id set so far
+#[derive(Debug, Clone, PartialEq)]
+struct Slab {
+    range: RangeInclusive<usize>,
+    expires: Vec<Option<i64>>,
+}
+
+// id -> expire, where ids are dense integers within a few ranges (the primary and overflow ones)
+// so lookups are a slab index instead of a tree walk, however far apart the ranges are,
+// with separate indexes by expiry so clearing only ever looks at what has actually expired
+//
+// both indexes are invalidated lazily: a renewed or removed id leaves its old entry behind,
+// which is dropped once it reaches the front (an entry only counts while the slab still has that expire),
+// so renewing is a slab write and a push rather than a tree update
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Expires {
+    // in range order, never overlapping
+    slabs: Vec<Slab>,
+    // ids outside every range, eg when there are none at all
+    others: BTreeMap<usize, i64>,
+    len: usize,
+    // (expire, id) as renewed, which is expiry order as long as the timeout stays the same
+    rolling: VecDeque<(i64, usize)>,
+    // everything that would be out of order in rolling, eg far future pins or a timeout tuned down
+    by_expire: BTreeSet<(i64, usize)>,
+}
+
+impl Expires {
+    pub fn new () -> Self {
+        Self::default()
+    }
+
+    // before anything is inserted (anything already there is moved in, so it is only slower otherwise)
+    pub fn add_range (&mut self, range: RangeInclusive<usize>) {
+        if range.is_empty() || self.slabs.iter().any(|slab| slab.range.start() <= range.end() && range.start() <= slab.range.end()) {
+            return;
+        }
+        let index = self.slabs.partition_point(|slab| slab.range.start() < range.start());
+        self.slabs.insert(index, Slab { range: range.clone(), expires: Vec::new() });
+        let moved = self.others.range(range).map(|(&id, &expire)| (id, expire)).collect::<Vec<_>>();
+        for (id, expire) in moved {
+            self.others.remove(&id);
+            *self.slot(id) = Some(expire);
+        }
+    }
+
+    pub fn len (&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty (&self) -> bool {
+        self.len == 0
+    }
+
+    fn slab (&self, id: usize) -> Option<&Slab> {
+        self.slabs.iter().find(|slab| slab.range.contains(&id))
+    }
+
+    pub fn get (&self, id: &usize) -> Option<&i64> {
+        match self.slab(*id) {
+            Some(slab) => slab.expires.get(id - slab.range.start())?.as_ref(),
+            None => self.others.get(id),
+        }
+    }
+
+    pub fn contains_key (&self, id: &usize) -> bool {
+        self.get(id).is_some()
+    }
+
+    // where an id in a range goes, growing its slab as needed
+    fn slot (&mut self, id: usize) -> &mut Option<i64> {
+        let slab = self.slabs.iter_mut().find(|slab| slab.range.contains(&id)).expect("Id outside every slab");
+        let index = id - slab.range.start();
+        if index >= slab.expires.len() {
+            slab.expires.resize(index + 1, None);
+        }
+        &mut slab.expires[index]
+    }
+
+    fn set (&mut self, id: usize, expire: i64) -> Option<i64> {
+        let previous = if self.slab(id).is_some() {
+            self.slot(id).replace(expire)
+        } else {
+            self.others.insert(id, expire)
+        };
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+
+    // the previous expire, if any, for any expire (eg far in the future)
+    pub fn insert (&mut self, id: usize, expire: i64) -> Option<i64> {
+        let previous = self.set(id, expire);
+        if let Some(previous) = previous {
+            self.by_expire.remove(&(previous, id));
+        }
+        self.by_expire.insert((expire, id));
+        self.trim();
+        previous
+    }
+
+    // the previous expire, if any, for the usual now + timeout, which is constant time
+    // as long as it is no earlier than the last one renewed
+    pub fn renew (&mut self, id: usize, expire: i64) -> Option<i64> {
+        let previous = self.set(id, expire);
+        if let Some(previous) = previous.filter(|&previous| previous > expire) {
+            // moving earlier, eg unpinning, would otherwise leave a far future entry behind for good
+            self.by_expire.remove(&(previous, id));
+        }
+        if self.rolling.back().is_none_or(|&(last, _)| last <= expire) {
+            self.rolling.push_back((expire, id));
+        } else {
+            self.by_expire.insert((expire, id));
+        }
+        self.trim();
+        previous
+    }
+
+    pub fn remove (&mut self, id: &usize) -> Option<i64> {
+        let expire = match self.slab(*id) {
+            Some(slab) => {
+                let index = id - slab.range.start();
+                self.slabs.iter_mut().find(|slab| slab.range.contains(id))?.expires.get_mut(index)?.take()
+            }
+            None => self.others.remove(id),
+        }?;
+        self.len -= 1;
+        self.trim();
+        Some(expire)
+    }
+
+    fn is_live (&self, &(expire, id): &(i64, usize)) -> bool {
+        self.get(&id) == Some(&expire)
+    }
+
+    // keeps the front of each index live, so they can be read without skipping anything
+    fn trim (&mut self) {
+        while self.rolling.front().is_some_and(|entry| !self.is_live(entry)) {
+            self.rolling.pop_front();
+        }
+        while self.by_expire.first().is_some_and(|entry| !self.is_live(entry)) {
+            self.by_expire.pop_first();
+        }
+    }
+
+    fn first (&self) -> Option<(i64, usize)> {
+        match (self.rolling.front(), self.by_expire.first()) {
+            (Some(&rolling), Some(&by_expire)) => Some(rolling.min(by_expire)),
+            (rolling, by_expire) => rolling.or(by_expire).copied(),
+        }
+    }
+
+    // the soonest to expire, if it has by now, in expiry order
+    pub fn pop_expired (&mut self, now: i64) -> Option<(usize, i64)> {
+        let (expire, id) = self.first()?;
+        if expire > now {
+            return None;
+        }
+        self.remove(&id);
+        Some((id, expire))
+    }
+
+    pub fn next_expire (&self) -> Option<i64> {
+        self.first().map(|(expire, _)| expire)
+    }
+
+    pub fn memory_bytes (&self) -> usize {
+        self.slabs.iter().map(|slab| vec_bytes::<Option<i64>>(slab.expires.capacity())).sum::<usize>()
+            + btree_bytes::<usize, i64>(self.others.len())
+            + vec_bytes::<(i64, usize)>(self.rolling.capacity())
+            + btree_bytes::<(i64, usize), ()>(self.by_expire.len())
+    }
+
+    // in id order within the ranges, then the others
+    pub fn iter (&self) -> impl Iterator<Item = (usize, i64)> + '_ {
+        self.slabs.iter()
+            .flat_map(|slab| slab.expires.iter()
+                .enumerate()
+                .filter_map(|(index, expire)| expire.map(|expire| (slab.range.start() + index, expire))))
+            .chain(self.others.iter().map(|(&id, &expire)| (id, expire)))
+    }
+}
+
+impl FromIterator<(usize, i64)> for Expires {
+    fn from_iter<I: IntoIterator<Item = (usize, i64)>> (iter: I) -> Self {
+        let mut expires = Self::new();
+        for (id, expire) in iter {
+            expires.insert(id, expire);
+        }
+        expires
+    }
+}
+
+impl From<BTreeMap<usize, i64>> for Expires {
+    fn from (map: BTreeMap<usize, i64>) -> Self {
+        map.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_get_remove () {
+        let mut expires = Expires::new();
+        expires.add_range(1..=10);
+        // far from it, so a slab of its own rather than one covering everything in between
+        expires.add_range(100_000_000_000..=100_000_000_005);
+        assert_eq!(expires.insert(5, 100), None);
+        assert_eq!(expires.insert(100_000_000_003, 50), None);
+        assert_eq!(expires.insert(20, 75), None);
+        assert_eq!(expires.insert(5, 200), Some(100));
+        assert_eq!(expires.get(&5), Some(&200));
+        assert_eq!(expires.get(&3), None);
+        assert_eq!(expires.get(&20), Some(&75));
+        assert_eq!(expires.get(&100), None);
+        assert_eq!(expires.len(), 3);
+        assert!(expires.memory_bytes() < 1024);
+        assert_eq!(expires.iter().collect::<Vec<_>>(), vec![(5, 200), (100_000_000_003, 50), (20, 75)]);
+        assert_eq!(expires.remove(&100_000_000_003), Some(50));
+        assert_eq!(expires.remove(&100_000_000_003), None);
+        assert_eq!(expires.remove(&20), Some(75));
+        assert_eq!(expires.len(), 1);
+    }
+
+    #[test]
+    fn pop_expired_in_expiry_order () {
+        let mut expires: Expires = [(1, 30), (2, 10), (3, 20), (4, 40)].into_iter().collect();
+        // a heartbeat moves it later
+        expires.insert(2, 35);
+        assert_eq!(expires.pop_expired(35), Some((3, 20)));
+        assert_eq!(expires.pop_expired(35), Some((1, 30)));
+        assert_eq!(expires.pop_expired(35), Some((2, 35)));
+        assert_eq!(expires.pop_expired(35), None);
+        assert_eq!(expires.iter().collect::<Vec<_>>(), vec![(4, 40)]);
+    }
+
+    #[test]
+    fn renew_lazily () {
+        let mut expires = Expires::new();
+        expires.add_range(1..=4);
+        for id in 1..=4 {
+            expires.renew(id, 10);
+        }
+        expires.renew(1, 20);
+        expires.remove(&2);
+        // pinned, then back to normal
+        expires.insert(3, i64::MAX);
+        expires.renew(3, 15);
+        // out of order (eg a timeout tuned down)
+        expires.renew(4, 12);
+        assert_eq!(expires.len(), 3);
+        assert_eq!(expires.next_expire(), Some(12));
+        assert_eq!(expires.pop_expired(100), Some((4, 12)));
+        assert_eq!(expires.pop_expired(100), Some((3, 15)));
+        assert_eq!(expires.pop_expired(100), Some((1, 20)));
+        assert_eq!(expires.pop_expired(100), None);
+        assert!(expires.rolling.is_empty() && expires.by_expire.is_empty());
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use serde::{Serialize, Serializer};
+
+
+// label -> the id ranges tagged with it, eg "gpu:1-8,cpu:9-100,gpu:200-207",
+// where every id has at most one label, and unlabeled ids only go to requests without a label
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Labels(pub BTreeMap<String, Vec<RangeInclusive<usize>>>);
+
+impl Labels {
+    pub fn ranges (&self, label: &str) -> Option<&[RangeInclusive<usize>]> {
+        self.0.get(label).map(Vec::as_slice)
+    }
+
+    pub fn label (&self, id: usize) -> Option<&str> {
+        self.0.iter()
+            .find(|(_, ranges)| ranges.iter().any(|range| range.contains(&id)))
+            .map(|(label, _)| label.as_str())
+    }
+}
+
+fn parse_range (s: &str) -> Option<RangeInclusive<usize>> {
+    let range = match s.split_once('-') {
+        Some((start, end)) => start.trim().parse().ok()?..=end.trim().parse().ok()?,
+        None => {
+            let id = s.trim().parse().ok()?;
+            id..=id
+        }
+    };
+    (!range.is_empty()).then_some(range)
+}
+
+impl FromStr for Labels {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Self, Self::Err> {
+        let mut labels = Labels::default();
+        for entry in s.split(',').filter(|entry| !entry.trim().is_empty()) {
+            let (label, range) = entry.split_once(':')
+                .ok_or_else(|| format!("Expected label:min-max, got '{}'", entry))?;
+            let range = parse_range(range).ok_or_else(|| format!("Bad range in '{}'", entry))?;
+            if let Some(other) = labels.0.values().flatten().find(|other| other.start() <= range.end() && range.start() <= other.end()) {
+                return Err(format!("Range in '{}' overlaps {}-{}", entry, other.start(), other.end()));
+            }
+            labels.0.entry(label.trim().to_string()).or_default().push(range);
+        }
+        Ok(labels)
+    }
+}
+
+impl Serialize for Labels {
+    fn serialize<S: Serializer> (&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|(label, ranges)| {
+            (label, ranges.iter().map(|range| format!("{}-{}", range.start(), range.end())).collect::<Vec<_>>())
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse () {
+        let labels: Labels = "gpu:1-8, cpu:9-100,gpu:200".parse().unwrap();
+        assert_eq!(labels.ranges("gpu"), Some(&[1..=8, 200..=200][..]));
+        assert_eq!(labels.label(9), Some("cpu"));
+        assert_eq!(labels.label(200), Some("gpu"));
+        assert_eq!(labels.label(101), None);
+        assert_eq!(serde_json::to_string(&labels).unwrap(), r#"{"cpu":["9-100"],"gpu":["1-8","200-200"]}"#);
+        assert_eq!("".parse::<Labels>(), Ok(Labels::default()));
+        assert!("gpu".parse::<Labels>().is_err());
+        assert!("gpu:8-1".parse::<Labels>().is_err());
+        assert!("gpu:1-8,cpu:8-9".parse::<Labels>().is_err());
+    }
+}
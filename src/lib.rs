@@ -0,0 +1,13 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+// the allocation/expiry core, without any of the server (axum/tokio) so it also builds for wasm32:
+// cargo rustc --lib --no-default-features --features std --target wasm32-unknown-unknown --crate-type cdylib
+extern crate alloc;
+
+pub mod time_provider;
+pub mod availables;
+pub mod expires;
+pub mod memory;
+pub mod pool;
+#[cfg(target_arch = "wasm32")]
+pub mod wasm;
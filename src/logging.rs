@@ -0,0 +1,219 @@
+use std::fmt;
+use std::net::UdpSocket;
+use std::os::unix::net::UnixDatagram;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+
+use sequential_id_generator::time_provider::{SystemTimeProvider, TimeProvider};
+
+
+// rfc 5424 numbering, most severe first
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Critical = 2,
+    Error = 3,
+    Warning = 4,
+    Notice = 5,
+}
+
+const FACILITIES: [(&str, u8); 12] = [
+    ("user", 1), ("daemon", 3), ("auth", 4), ("syslog", 5),
+    ("local0", 16), ("local1", 17), ("local2", 18), ("local3", 19),
+    ("local4", 20), ("local5", 21), ("local6", 22), ("local7", 23),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Facility(u8);
+
+impl Default for Facility {
+    fn default () -> Self {
+        Facility(16)
+    }
+}
+
+impl FromStr for Facility {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Self, Self::Err> {
+        FACILITIES.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s.trim()))
+            .map(|&(_, code)| Facility(code))
+            .ok_or_else(|| format!("Invalid facility: {}", s))
+    }
+}
+
+impl fmt::Display for Facility {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = FACILITIES.iter().find(|&&(_, code)| code == self.0).map_or("?", |(name, _)| name);
+        write!(f, "{}", name)
+    }
+}
+
+impl Serialize for Facility {
+    fn serialize<S: Serializer> (&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+// where every event line goes, picked once at startup
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(tag = "target", rename_all = "lowercase")]
+pub enum LogConfig {
+    // one json object per line, as always
+    #[default]
+    Stderr,
+    // a local socket path (eg /dev/log) or a udp host:port (eg an rsyslog relay)
+    Syslog { addr: String, facility: Facility },
+}
+
+enum SyslogSocket {
+    Udp(UdpSocket),
+    Unix(UnixDatagram),
+}
+
+struct Syslog {
+    socket: SyslogSocket,
+    facility: Facility,
+    hostname: String,
+}
+
+impl Syslog {
+    fn connect (addr: &str, facility: Facility) -> std::io::Result<Self> {
+        let socket = if addr.starts_with('/') {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(addr)?;
+            socket.set_nonblocking(true)?;
+            SyslogSocket::Unix(socket)
+        } else {
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            socket.connect(addr)?;
+            socket.set_nonblocking(true)?;
+            SyslogSocket::Udp(socket)
+        };
+        let hostname = std::env::var("HOSTNAME").ok()
+            .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+            .map(|hostname| hostname.trim().to_string())
+            .filter(|hostname| !hostname.is_empty())
+            .unwrap_or("-".to_string());
+        Ok(Self { socket, facility, hostname })
+    }
+
+    // never waits, since this is called with the pool lock held: a full local socket (eg a stalled syslog daemon)
+    // is a WouldBlock error, and so a line on stderr instead, rather than every request freezing
+    fn send (&self, severity: Severity, event: &Value) -> std::io::Result<usize> {
+        let line = syslog_line(self.facility, severity, SystemTimeProvider {}.unix_ts_ms(), &self.hostname, event);
+        match &self.socket {
+            SyslogSocket::Udp(socket) => socket.send(line.as_bytes()),
+            SyslogSocket::Unix(socket) => socket.send(line.as_bytes()),
+        }
+    }
+}
+
+static SYSLOG: OnceLock<Syslog> = OnceLock::new();
+
+// before anything is logged, since until then (and on any syslog send failure) it is stderr
+pub fn init (config: &LogConfig) -> std::io::Result<()> {
+    if let LogConfig::Syslog { addr, facility } = config {
+        let _ = SYSLOG.set(Syslog::connect(addr, *facility)?);
+    }
+    Ok(())
+}
+
+// days since the epoch to (year, month, day), see http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days (days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + (month <= 2) as i64, month, day)
+}
+
+fn rfc3339 (unix_ts_ms: i64) -> String {
+    let (year, month, day) = civil_from_days(unix_ts_ms.div_euclid(86_400_000));
+    let ms = unix_ts_ms.rem_euclid(86_400_000);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, ms % 1000,
+    )
+}
+
+// <pri>1 timestamp hostname app-name procid msgid structured-data msg, with the event name as msgid and the json line as msg
+fn syslog_line (facility: Facility, severity: Severity, unix_ts_ms: i64, hostname: &str, event: &Value) -> String {
+    let msgid = event["event"].as_str()
+        .filter(|name| !name.is_empty() && name.len() <= 32)
+        .unwrap_or("-");
+    format!(
+        "<{}>1 {} {} {} {} {} - {}",
+        facility.0 as usize * 8 + severity as usize,
+        rfc3339(unix_ts_ms), hostname, env!("CARGO_PKG_NAME"), std::process::id(), msgid, event,
+    )
+}
+
+pub fn log (severity: Severity, event: &Value) {
+    if SYSLOG.get().is_some_and(|syslog| syslog.send(severity, event).is_ok()) {
+        return;
+    }
+    eprintln!("{}", event);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn timestamps () {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(rfc3339(951_782_400_000), "2000-02-29T00:00:00.000Z");
+        assert_eq!(rfc3339(1_792_060_898_413), "2026-10-15T10:41:38.413Z");
+    }
+
+    #[test]
+    fn facilities () {
+        assert_eq!("LOCAL3".parse::<Facility>(), Ok(Facility(19)));
+        assert_eq!("daemon".parse::<Facility>().unwrap().to_string(), "daemon");
+        assert!("kern".parse::<Facility>().is_err());
+    }
+
+    #[test]
+    fn line () {
+        let event = json!({ "event": "timeout_tuned", "from": 3000, "to": 2000 });
+        let line = syslog_line(Facility::default(), Severity::Notice, 0, "host-a", &event);
+        assert_eq!(line, format!(
+            r#"<133>1 1970-01-01T00:00:00.000Z host-a sequential-id-generator {} timeout_tuned - {{"event":"timeout_tuned","from":3000,"to":2000}}"#,
+            std::process::id(),
+        ));
+    }
+
+    #[test]
+    fn full_socket_never_blocks () {
+        let path = std::env::temp_dir().join(format!("syslog-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _server = UnixDatagram::bind(&path).unwrap();
+        let syslog = Syslog::connect(path.to_str().unwrap(), Facility::default()).unwrap();
+        // nobody reading, so the queue fills up
+        let error = (0..100_000)
+            .find_map(|_| syslog.send(Severity::Notice, &json!({ "event": "anomaly" })).err())
+            .expect("The socket never filled up");
+        assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn udp () {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let syslog = Syslog::connect(&server.local_addr().unwrap().to_string(), Facility(3)).unwrap();
+        syslog.send(Severity::Error, &json!({ "event": "anomaly" })).unwrap();
+        let mut buf = [0; 1024];
+        let len = server.recv(&mut buf).unwrap();
+        assert!(String::from_utf8_lossy(&buf[..len]).starts_with("<27>1 "));
+    }
+}
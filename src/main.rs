@@ -1,62 +1,334 @@
 
-mod time_provider;
-use time_provider::{TimeProvider, SystemTimeProvider};
+use sequential_id_generator::time_provider::{FixedTimeProvider, SystemTimeProvider, TimeProvider};
+use sequential_id_generator::availables::Availables;
+use sequential_id_generator::expires::Expires;
+use sequential_id_generator::memory::btree_bytes;
+use sequential_id_generator::pool::{HeartbeatError, Pool};
+mod config;
+use config::{
+    Config,
+    DEFAULT_MIN_HEARTBEAT_INTERVAL,
+    DEFAULT_BACKOFF_BASE,
+    DEFAULT_BACKOFF_MAX,
+    DEFAULT_STATS_HISTORY_HOURS,
+    DEFAULT_ANOMALY_AGE_FACTOR,
+    DEFAULT_ANOMALY_EXPIRATIONS,
+    DEFAULT_ANOMALY_ADDRESS_CHANGES,
+    DEFAULT_SLOW_OPERATION_THRESHOLD,
+    DEFAULT_HEARTBEAT_SAMPLES,
+};
+mod maintenance;
+use maintenance::MaintenanceWindows;
+mod stats;
+use stats::{ReclaimPasses, StatsHistory, StatsMinute};
+mod anomalies;
+use anomalies::{Anomaly, AnomalyThresholds, ExpirationsByOwner, find_anomalies};
+mod requester;
+use requester::Requester;
+mod auth;
+use auth::{ApiKeys, AuthLayer, Mechanism, Principal, Role, require_auth};
+mod quota;
+use quota::QuotaTracker;
+mod cadence;
+use cadence::HeartbeatCadence;
+mod shedding;
+use shedding::{LoadShedder, shed_load, track_load};
+mod tcp;
+mod reporter;
+use reporter::{ErrorReport, Level, LogReporter, SharedReporter};
+mod logging;
+use logging::Severity;
+mod self_check;
+use self_check::SelfCheck;
+mod waiters;
+use waiters::Waiters;
+mod callbacks;
+mod labels;
+use labels::Labels;
+mod seed;
+use seed::Seed;
+use callbacks::{CallbackSender, HttpCallbacks};
 
-use std::env;
-use std::sync::{Arc, Mutex, MutexGuard};
-use std::collections::{BTreeMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use std::collections::BTreeMap;
 
 use axum::{
-	routing::get,
-	extract::{Path, State},
-    response::Json,
+	routing::{get, post},
+	body::Body,
+	extract::{ConnectInfo, Extension, Path, Query, State},
+    http::{header, HeaderMap},
+    middleware,
+    response::{IntoResponse, Json, Response},
 	Router,
 };
 
+use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 
 use lazy_static::lazy_static;
 
 
-const DEFAULT_PORT: u16 = 3000;
-const DEFAULT_MAX: usize = 65535;
-const DEFAULT_MIN: usize = 1;
-const DEFAULT_TIMEOUT: i64 = 3000;
+const DEFAULT_TOP_N: usize = 20;
 
 const ERROR_CODE_NO_ID_AVAILBLE: usize = 1;
 const ERROR_CODE_ID_EXPIRED: usize = 2;
 const ERROR_CODE_ID_NONEXISTENT: usize = 3;
+const ERROR_CODE_TICKET_NONEXISTENT: usize = 4;
+const ERROR_CODE_TICKET_EXPIRED: usize = 5;
+const ERROR_CODE_ID_UNAVAILABLE: usize = 6;
+const ERROR_CODE_ID_PENDING: usize = 7;
+const ERROR_CODE_MAINTENANCE: usize = 8;
+const ERROR_CODE_HEARTBEAT_THROTTLED: usize = 9;
+const ERROR_CODE_UNAUTHORIZED: usize = 10;
+const ERROR_CODE_FORBIDDEN: usize = 11;
+const ERROR_CODE_QUOTA_EXCEEDED: usize = 12;
+const ERROR_CODE_OVERLOADED: usize = 13;
+const ERROR_CODE_INVALID_TIME: usize = 14;
+const ERROR_CODE_NO_IDENTITY: usize = 15;
+const ERROR_CODE_INVALID_CALLBACK: usize = 16;
+const ERROR_CODE_NOT_PINNED: usize = 17;
+const ERROR_CODE_UNKNOWN_LABEL: usize = 18;
+const ERROR_CODE_ID_PINNED: usize = 19;
 
 
 lazy_static! {
-    static ref ERROR_CODE_MSGS: BTreeMap<usize, &'static str> = vec![
+    static ref ERROR_CODE_MSGS: BTreeMap<usize, &'static str> = [
         (ERROR_CODE_NO_ID_AVAILBLE, "No id available!"),
         (ERROR_CODE_ID_EXPIRED, "Id expired!"),
         (ERROR_CODE_ID_NONEXISTENT, "Id nonexistent!"),
+        (ERROR_CODE_TICKET_NONEXISTENT, "Ticket nonexistent!"),
+        (ERROR_CODE_TICKET_EXPIRED, "Ticket expired!"),
+        (ERROR_CODE_ID_UNAVAILABLE, "Id unavailable!"),
+        (ERROR_CODE_ID_PENDING, "Id reservation pending!"),
+        (ERROR_CODE_MAINTENANCE, "Allocation paused for maintenance!"),
+        (ERROR_CODE_HEARTBEAT_THROTTLED, "Heartbeat too frequent!"),
+        (ERROR_CODE_UNAUTHORIZED, "Unauthorized!"),
+        (ERROR_CODE_FORBIDDEN, "Forbidden!"),
+        (ERROR_CODE_QUOTA_EXCEEDED, "Quota exceeded!"),
+        (ERROR_CODE_OVERLOADED, "Too busy, try again later!"),
+        (ERROR_CODE_INVALID_TIME, "Invalid time!"),
+        (ERROR_CODE_NO_IDENTITY, "Client identity required!"),
+        (ERROR_CODE_INVALID_CALLBACK, "Invalid callback!"),
+        (ERROR_CODE_NOT_PINNED, "Id not pinned!"),
+        (ERROR_CODE_UNKNOWN_LABEL, "Unknown label!"),
+        (ERROR_CODE_ID_PINNED, "Id pinned!"),
     ].iter().copied().collect::<BTreeMap<_, _>>();
+
+    // errors where retrying later can succeed, so clients get told how long to wait
+    static ref BACKOFF_ERROR_CODES: Vec<usize> = vec![
+        ERROR_CODE_NO_ID_AVAILBLE,
+        ERROR_CODE_HEARTBEAT_THROTTLED,
+    ];
 }
 
 static SYSTEM_TIME_PROVIDER: SystemTimeProvider = SystemTimeProvider {};
 
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Lease {
+    allocated: i64,
+    // last time it was allocated or heartbeat
+    renewed: i64,
+    renewals: usize,
+    owner: Option<String>,
+    // the api key it was allocated with, if any
+    principal: Option<String>,
+    // POSTed to if it expires
+    callback: Option<String>,
+    // exempt from expiry (with an expire of i64::MAX) until unpinned
+    pinned: Option<Pin>,
+    allocated_by: Option<Requester>,
+    renewed_by: Option<Requester>,
+    // heartbeats from a different address than the one before
+    address_changes: usize,
+}
+
+impl Lease {
+    fn renewed_by (&mut self, requester: Requester) {
+        let previous = self.renewed_by.as_ref().or(self.allocated_by.as_ref());
+        if previous.is_some_and(|previous| previous.addr != requester.addr) {
+            self.address_changes += 1;
+        }
+        self.renewed_by = Some(requester);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct Pin {
+    at: i64,
+    // the admin principal, if the admin api needed a key
+    by: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Backoff {
+    failures: u32,
+    last: i64,
+}
+
 struct AppState<'a> {
-    timeout: i64,
-    expires: BTreeMap<usize, i64>,
-    availables: VecDeque<usize>,
-    time_provider: &'a(dyn TimeProvider + Send + Sync),
+    pool: Pool<'a>,
+    min_heartbeat_interval: i64,
+    leases: BTreeMap<usize, Lease>,
+    // ticket -> the (id, exp) bound to it, if any yet
+    tickets: BTreeMap<usize, Option<(usize, i64)>>,
+    tickets_waiting: Waiters,
+    // waiting ticket -> (principal, when) charged to its quota, refunded if cancelled before it gets an id
+    ticket_charges: BTreeMap<usize, (String, i64)>,
+    ticket_last: usize,
+    // id -> when its reservation becomes a normal lease, ordered by that time like expires
+    reservations: Expires,
+    maintenance_windows: MaintenanceWindows,
+    backoff_base: i64,
+    backoff_max: i64,
+    // client -> consecutive retryable errors
+    backoffs: BTreeMap<String, Backoff>,
+    allocations_total: usize,
+    expirations_total: usize,
+    releases_total: usize,
+    reclaim_passes: ReclaimPasses,
+    stats_history: StatsHistory,
+    expirations_by_owner: ExpirationsByOwner,
+    anomaly_thresholds: AnomalyThresholds,
+    // as of the last periodic check
+    anomalies: Vec<Anomaly>,
+    anomalies_checked: i64,
+    trusted_proxies: Vec<IpAddr>,
+    quotas: QuotaTracker,
+    labels: Labels,
+    slow_operation_threshold: Duration,
+    heartbeat_cadence: HeartbeatCadence,
+    // when set, the reaper keeps the timeout at the recommended one, within these
+    timeout_bounds: Option<RangeInclusive<i64>>,
+    // only with --self-check
+    self_check: Option<SelfCheck>,
+    // lease callbacks are refused while this is unset
+    callbacks: Option<Arc<dyn CallbackSender>>,
+    // anomalies and failures an operator should hear about, just logged unless SENTRY_DSN is set too
+    reporter: SharedReporter,
+}
+
+#[derive(Deserialize)]
+struct NextParams {
+    owner: Option<String>,
+    callback: Option<String>,
+    label: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClockParams {
+    // unix ms for set, ms to move forward by for advance
+    ms: i64,
 }
 
-fn env_var_parse<T: std::str::FromStr> (name: &str, default: T) -> T {
-    match env::var(name) {
-        Ok(s) => s.parse::<T>().unwrap_or(default),
-        _ => default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TopBy {
+    #[default]
+    Age,
+    Renewals,
+}
+
+#[derive(Deserialize)]
+struct TopParams {
+    #[serde(default)]
+    by: TopBy,
+    n: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct ReserveParams {
+    at: i64,
+    id: Option<usize>,
+}
+
+#[derive(Debug, PartialEq)]
+enum TicketStatus {
+    Waiting(usize),
+    Bound(usize, i64),
+}
+
+impl<'a> AppState<'a> {
+    fn new (pool: Pool<'a>) -> Self {
+        Self {
+            pool,
+            min_heartbeat_interval: DEFAULT_MIN_HEARTBEAT_INTERVAL,
+            leases: BTreeMap::new(),
+            tickets: BTreeMap::new(),
+            tickets_waiting: Waiters::default(),
+            ticket_charges: BTreeMap::new(),
+            ticket_last: 0,
+            reservations: Expires::new(),
+            maintenance_windows: MaintenanceWindows::default(),
+            backoff_base: DEFAULT_BACKOFF_BASE,
+            backoff_max: DEFAULT_BACKOFF_MAX,
+            backoffs: BTreeMap::new(),
+            allocations_total: 0,
+            expirations_total: 0,
+            releases_total: 0,
+            reclaim_passes: ReclaimPasses::default(),
+            stats_history: StatsHistory::new(DEFAULT_STATS_HISTORY_HOURS * 60),
+            expirations_by_owner: ExpirationsByOwner::default(),
+            anomaly_thresholds: AnomalyThresholds {
+                age_factor: DEFAULT_ANOMALY_AGE_FACTOR,
+                expirations: DEFAULT_ANOMALY_EXPIRATIONS,
+                address_changes: DEFAULT_ANOMALY_ADDRESS_CHANGES,
+            },
+            anomalies: vec![],
+            anomalies_checked: 0,
+            trusted_proxies: vec![],
+            quotas: QuotaTracker::default(),
+            labels: Labels::default(),
+            slow_operation_threshold: Duration::from_millis(DEFAULT_SLOW_OPERATION_THRESHOLD),
+            heartbeat_cadence: HeartbeatCadence::new(DEFAULT_HEARTBEAT_SAMPLES),
+            timeout_bounds: None,
+            self_check: None,
+            callbacks: None,
+            reporter: Arc::new(LogReporter),
+        }
+    }
+
+    fn in_maintenance (&self) -> bool {
+        self.maintenance_windows.contains(self.pool.now())
+    }
+
+    // every id is always exactly one of these
+    fn total (&self) -> usize {
+        self.pool.total() + self.reservations.len()
+    }
+
+    fn record_stats (&mut self, allocations: usize, expirations: usize, releases: usize) {
+        let now = self.pool.now();
+        let (leased, total) = (self.pool.leased(), self.total());
+        self.allocations_total += allocations;
+        self.expirations_total += expirations;
+        self.releases_total += releases;
+        let current = self.stats_history.current(now);
+        current.allocations += allocations;
+        current.expirations += expirations;
+        current.releases += releases;
+        current.leased = leased;
+        current.total = total;
     }
 }
 
-fn json_success (id: usize, exp: i64) -> Json<Value> {
-    Json(json!({
-        "id": id,
-        "exp": exp,
-    }))
+fn json_success (id: usize, exp: i64, overflow: bool) -> Json<Value> {
+    if overflow {
+        // overflow ids are temporary: clients should release them once the primary range frees up
+        Json(json!({
+            "id": id,
+            "exp": exp,
+            "overflow": true,
+        }))
+    } else {
+        Json(json!({
+            "id": id,
+            "exp": exp,
+        }))
+    }
 }
 
 fn json_error (code: usize) -> Json<Value> {
@@ -68,95 +340,1134 @@ fn json_error (code: usize) -> Json<Value> {
     }))
 }
 
+// records another retryable error for the client, returning how long it should wait before trying again
+fn record_backoff (client: &str, state: &mut MutexGuard<AppState>) -> i64 {
+    let now = state.pool.now();
+    let (base, max) = (state.backoff_base, state.backoff_max);
+    // clients that have been quiet for a while start over, which also keeps this from growing forever
+    state.backoffs.retain(|_, backoff| now - backoff.last <= max * 2);
+    let backoff = state.backoffs.entry(client.to_string())
+        .or_insert(Backoff { failures: 0, last: now });
+    backoff.failures = backoff.failures.saturating_add(1);
+    backoff.last = now;
+    base.saturating_mul(1 << (backoff.failures - 1).min(32)).min(max)
+}
+
+fn reset_backoff (client: &str, state: &mut MutexGuard<AppState>) {
+    state.backoffs.remove(client);
+}
+
+fn json_error_for (code: usize, client: &str, state: &mut MutexGuard<AppState>) -> Json<Value> {
+    let mut json = json_error(code);
+    if BACKOFF_ERROR_CODES.contains(&code) {
+        json.0["error"]["backoff_ms"] = json!(record_backoff(client, state));
+    }
+    json
+}
+
 fn clear_expired (state: &mut MutexGuard<AppState>) -> usize {
-    let now = state.time_provider.unix_ts_ms();
-    let mut expireds = vec![];
-    for (&id, &expire) in state.expires.iter() {
-        if expire <= now {
-            expireds.push(id);
-        }
-    }
-    for id in expireds.iter() {
-        state.expires.remove(id);
-        state.availables.push_back(*id);
-    }
-    // TODO: use https://doc.rust-lang.org/stable/std/collections/struct.BTreeMap.html#method.extract_if
-    // let count_old = availables.len();
-    // for (id, expire) in expires.extract_if(|&id, &mut expire| expire < now) {
-    //     availables.push_back(id);
-    // }
-    // availables.len() - count_old
+    let expireds = state.pool.clear_expired();
+    let now = state.pool.now();
+    for &id in expireds.iter() {
+        let Some(lease) = state.leases.remove(&id) else {
+            continue;
+        };
+        if let (Some(callback), Some(callbacks)) = (&lease.callback, &state.callbacks) {
+            callbacks.send(callback, json!({
+                "event": "lease_expired",
+                "id": id,
+                "owner": lease.owner,
+                "allocated": lease.allocated,
+                "renewed": lease.renewed,
+                "at": now,
+            }));
+        }
+        if let Some(owner) = lease.owner {
+            state.expirations_by_owner.record(owner);
+        }
+    }
+    state.reclaim_passes.record(expireds.len());
+    state.record_stats(0, expireds.len(), 0);
     expireds.len()
 }
 
-fn get_next_impl (mut state: MutexGuard<AppState>) -> Result<(usize, i64), usize> {
-    clear_expired(&mut state);
+// reservations whose time has come become leases as if allocated at exactly that time
+fn activate_reservations (state: &mut MutexGuard<AppState>) -> usize {
+    let now = state.pool.now();
+    // only ever looks at the ones due, however many are still in the future
+    let mut actives = vec![];
+    while let Some((id, at)) = state.reservations.pop_expired(now) {
+        actives.push((id, at));
+    }
+    for &(id, at) in actives.iter() {
+        state.pool.lease(id, at);
+        state.leases.insert(id, Lease { allocated: at, renewed: at, ..Lease::default() });
+    }
+    state.record_stats(actives.len(), 0, 0);
+    actives.len()
+}
 
-    if let Some(id_next) = state.availables.pop_front() {
-        let now = state.time_provider.unix_ts_ms();
-        let expire = now + state.timeout;
-        state.expires.insert(id_next, expire);
-        Ok((id_next, expire))
-    } else {
-        Err(ERROR_CODE_NO_ID_AVAILBLE)
+// ids already in use by processes from before the service, leased to them from the start,
+// and pinned when they have no expire, since those processes may never heartbeat
+fn seed_leases (seeds: Vec<Seed>, state: &mut AppState) -> Result<usize, String> {
+    let now = state.pool.now();
+    for seed in seeds.iter() {
+        if state.pool.take(Some(seed.id)).is_none() {
+            return Err(format!("Id {} is outside the pool or seeded twice", seed.id));
+        }
+        state.pool.expires.insert(seed.id, seed.expire.unwrap_or(i64::MAX));
+        state.leases.insert(seed.id, Lease {
+            allocated: now,
+            renewed: now,
+            owner: seed.owner.clone(),
+            pinned: seed.expire.is_none().then_some(Pin { at: now, by: None }),
+            ..Lease::default()
+        });
     }
+    Ok(seeds.len())
+}
+
+// brings all the time based state up to date, before anything reads it
+fn refresh (state: &mut MutexGuard<AppState>) {
+    activate_reservations(state);
+    clear_expired(state);
+    bind_tickets(state);
 }
 
-async fn get_next (State(state): State<Arc<Mutex<AppState<'_>>>>) -> Json<Value> {
-    let state = state.lock().expect("Poisoned get_next_impl mutex");
-    match get_next_impl(state) {
-        Ok((id_next, expire)) => json_success(id_next, expire),
+// any id, or only one within the ranges (of a label)
+fn allocate (ranges: Option<&[RangeInclusive<usize>]>, state: &mut MutexGuard<AppState>) -> Option<(usize, i64)> {
+    let take = |pool: &mut Pool| match ranges {
+        Some(ranges) => pool.take_in(ranges),
+        None => pool.take(None),
+    };
+    let id_next = match take(&mut state.pool) {
+        Some(id_next) => id_next,
+        None => {
+            // the last batch may have left some expired ids behind
+            clear_expired(state);
+            take(&mut state.pool)?
+        }
+    };
+    let now = state.pool.now();
+    let expire = state.pool.lease(id_next, now);
+    state.leases.insert(id_next, Lease { allocated: now, renewed: now, ..Lease::default() });
+    state.record_stats(1, 0, 0);
+    Some((id_next, expire))
+}
+
+// waiting tickets get first claim on any available ids, in the order they were created
+fn bind_tickets (state: &mut MutexGuard<AppState>) -> usize {
+    if state.in_maintenance() {
+        // they keep their place in line until the window closes
+        return 0;
+    }
+    let mut bound = 0;
+    while let Some(ticket) = state.tickets_waiting.front() {
+        if let Some(lease) = allocate(None, state) {
+            state.tickets_waiting.pop_front();
+            state.ticket_charges.remove(&ticket);
+            state.tickets.insert(ticket, Some(lease));
+            bound += 1;
+        } else {
+            break;
+        }
+    }
+    bound
+}
+
+// only ids with the label, when there is one, while requests without one can get any id
+fn get_next_impl (label: Option<&str>, state: &mut MutexGuard<AppState>) -> Result<(usize, i64), usize> {
+    let ranges = match label {
+        Some(label) => Some(state.labels.ranges(label).ok_or(ERROR_CODE_UNKNOWN_LABEL)?.to_vec()),
+        None => None,
+    };
+    refresh(state);
+
+    if state.in_maintenance() {
+        return Err(ERROR_CODE_MAINTENANCE);
+    }
+
+    allocate(ranges.as_deref(), state).ok_or(ERROR_CODE_NO_ID_AVAILBLE)
+}
+
+// follows the observed heartbeat cadence, if the operator allowed it to
+fn tune_timeout (state: &mut MutexGuard<AppState>) -> Option<i64> {
+    let bounds = state.timeout_bounds.clone()?;
+    let recommended = state.heartbeat_cadence.recommend()?.recommended;
+    let timeout = recommended.clamp(*bounds.start(), *bounds.end());
+    if timeout != state.pool.timeout {
+        logging::log(Severity::Notice, &json!({ "event": "timeout_tuned", "from": state.pool.timeout, "to": timeout }));
+        // only affects leases from their next heartbeat on
+        state.pool.timeout = timeout;
+    }
+    Some(timeout)
+}
+
+async fn get_timeout (State(state): State<Arc<Mutex<AppState<'_>>>>) -> Json<Value> {
+    let state = state.lock().expect("Poisoned get_timeout mutex");
+    Json(json!({
+        "timeout": state.pool.timeout,
+        "recommendation": state.heartbeat_cadence.recommend(),
+        "auto": state.timeout_bounds.as_ref().map(|bounds| json!({ "min": bounds.start(), "max": bounds.end() })),
+    }))
+}
+
+// logs the operations that held the lock for longer than slow_operation_threshold
+fn timed<'a, T> (operation: &str, state: &mut MutexGuard<AppState<'a>>, f: impl FnOnce(&mut MutexGuard<AppState<'a>>) -> T) -> T {
+    let start = Instant::now();
+    let result = f(state);
+    if let Some(event) = slow_operation(operation, start.elapsed(), state) {
+        logging::log(Severity::Warning, &event);
+    }
+    result
+}
+
+fn slow_operation (operation: &str, elapsed: Duration, state: &AppState) -> Option<Value> {
+    if elapsed < state.slow_operation_threshold {
+        return None;
+    }
+    // the pool size is usually what makes it slow
+    Some(json!({
+        "event": "slow_operation",
+        "operation": operation,
+        "elapsed_ms": elapsed.as_secs_f64() * 1000.0,
+        "leased": state.pool.leased(),
+        "total": state.total(),
+        "tickets_waiting": state.tickets_waiting.len(),
+    }))
+}
+
+// ids count against a principal's quota however they are taken: /next, /tickets or /reserve
+fn check_quota (principal: Option<&str>, state: &MutexGuard<AppState>) -> Result<(), usize> {
+    match principal.map(|principal| state.quotas.check(principal, state.pool.now())) {
+        Some(Err(_)) => Err(ERROR_CODE_QUOTA_EXCEEDED),
+        _ => Ok(()),
+    }
+}
+
+fn json_quota_exceeded (principal: Option<&str>, state: &MutexGuard<AppState>) -> Json<Value> {
+    let mut json = json_error(ERROR_CODE_QUOTA_EXCEEDED);
+    let now = state.pool.now();
+    if let Some(status) = principal.and_then(|principal| state.quotas.status(principal, now)) {
+        json.0["error"]["quota"] = json!(status);
+        // no point trying again before it resets
+        json.0["error"]["backoff_ms"] = json!(status.reset - now);
+    }
+    json
+}
+
+fn add_quota_remaining (json: &mut Json<Value>, principal: Option<&str>, state: &MutexGuard<AppState>) {
+    if let Some(status) = principal.and_then(|principal| state.quotas.status(principal, state.pool.now())) {
+        json.0["quota_remaining"] = json!(status.remaining);
+    }
+}
+
+// everything /next does around get_next_impl, with the lease as well as the response when there is one
+fn next_json (
+    owner: Option<String>,
+    label: Option<&str>,
+    requester: Requester,
+    principal: Option<String>,
+    state: &mut MutexGuard<AppState>,
+) -> (Option<(usize, i64)>, Json<Value>) {
+    let client = requester.addr.clone();
+    let now = state.pool.now();
+    if check_quota(principal.as_deref(), state).is_err() {
+        return (None, json_quota_exceeded(principal.as_deref(), state));
+    }
+    match timed("next", state, |state| get_next_impl(label, state)) {
+        Ok((id_next, expire)) => {
+            reset_backoff(&client, state);
+            if let Some(lease) = state.leases.get_mut(&id_next) {
+                lease.owner = owner;
+                lease.principal = principal.clone();
+                lease.allocated_by = Some(requester);
+            }
+            let quota = principal.and_then(|principal| state.quotas.record(&principal, now));
+            let mut json = json_success(id_next, expire, state.pool.is_overflow(id_next));
+            if let Some(quota) = quota {
+                json.0["quota_remaining"] = json!(quota.remaining);
+            }
+            (Some((id_next, expire)), json)
+        }
+        Err(code) => (None, json_error_for(code, &client, state))
+    }
+}
+
+async fn get_next (
+    Query(params): Query<NextParams>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    principal: Option<Extension<Principal>>,
+    State(state): State<Arc<Mutex<AppState<'_>>>>,
+) -> Json<Value> {
+    let mut state = state.lock().expect("Poisoned get_next_impl mutex");
+    if let Some(callback) = params.callback.as_deref() {
+        if state.callbacks.is_none() || !callbacks::valid_url(callback) {
+            return json_error(ERROR_CODE_INVALID_CALLBACK);
+        }
+    }
+    let requester = Requester::new(addr.ip(), &headers, &state.trusted_proxies);
+    let principal = principal.map(|Extension(principal)| principal.name);
+    let (lease, json) = next_json(params.owner, params.label.as_deref(), requester, principal, &mut state);
+    if let Some(lease) = lease.and_then(|(id, _)| state.leases.get_mut(&id)) {
+        lease.callback = params.callback;
+    }
+    json
+}
+
+// the live leases of whoever is asking, by owner and/or api key (both have to match when both are known),
+// so a restarted client can pick up where it left off
+fn get_mine_impl (owner: Option<&str>, principal: Option<&str>, state: &mut MutexGuard<AppState>) -> Result<Vec<usize>, usize> {
+    if owner.is_none() && principal.is_none() {
+        return Err(ERROR_CODE_NO_IDENTITY);
+    }
+    refresh(state);
+    Ok(state.leases.iter()
+        .filter(|(_, lease)| owner.is_none_or(|owner| lease.owner.as_deref() == Some(owner)))
+        .filter(|(_, lease)| principal.is_none_or(|principal| lease.principal.as_deref() == Some(principal)))
+        .map(|(&id, _)| id)
+        .filter(|&id| state.pool.is_leased(id))
+        .collect())
+}
+
+async fn get_mine (
+    Query(params): Query<NextParams>,
+    principal: Option<Extension<Principal>>,
+    State(state): State<Arc<Mutex<AppState<'_>>>>,
+) -> Json<Value> {
+    let mut state = state.lock().expect("Poisoned get_mine mutex");
+    let principal = principal.map(|Extension(principal)| principal.name);
+    match timed("mine", &mut state, |state| get_mine_impl(params.owner.as_deref(), principal.as_deref(), state)) {
+        Ok(ids) => {
+            let now = state.pool.now();
+            let leases = ids.into_iter()
+                .map(|id| {
+                    let mut json = json_lease(id, &state.leases[&id], &state);
+                    json["ttl_ms"] = json!(state.pool.expires.get(&id).map(|expire| expire - now));
+                    json["overflow"] = json!(state.pool.is_overflow(id));
+                    json
+                })
+                .collect::<Vec<_>>();
+            Json(json!({ "now": now, "leases": leases }))
+        }
         Err(code) => json_error(code)
     }
 }
 
-fn get_heartbeat_impl (id: usize, mut state: MutexGuard<AppState>) -> Result<i64, usize> {
-    if let Some(&expire) = state.expires.get(&id) {
-        let now = state.time_provider.unix_ts_ms();
-        if expire > now {
-            let expire = now + state.timeout;
-            state.expires.insert(id, expire);
-            Ok(expire)
-        } else {
-            // Connecting client should take this error and request a new (next) id
-            // TODO: warn loudly! this means it potentially used a shared id for some period
-            Err(ERROR_CODE_ID_EXPIRED)
+// hands a lease back early, unless it has already expired (and maybe gone to someone else since) or an admin pinned it
+fn release_impl (id: usize, expire: i64, state: &mut MutexGuard<AppState>) -> bool {
+    if state.pool.expires.get(&id) != Some(&expire) || state.leases.get(&id).is_some_and(|lease| lease.pinned.is_some()) {
+        return false;
+    }
+    state.leases.remove(&id);
+    let released = state.pool.free(id);
+    state.record_stats(0, 0, released as usize);
+    released
+}
+
+// renews a held lease, as long as it is still the same one
+fn hold_tick_impl (id: usize, expire: i64, state: &mut MutexGuard<AppState>) -> Result<i64, usize> {
+    let pinned = state.leases.get(&id).is_some_and(|lease| lease.pinned.is_some());
+    if !pinned && (state.pool.expires.get(&id) != Some(&expire) || !state.pool.is_leased(id)) {
+        return Err(ERROR_CODE_ID_EXPIRED);
+    }
+    get_heartbeat_impl(id, state)
+}
+
+// resolves once the response body is dropped, ie the client disconnected, without sending anything
+async fn body_closed (sender: &mut hyper::body::Sender) {
+    std::future::poll_fn(|cx| match sender.poll_ready(cx) {
+        Poll::Ready(Err(_)) => Poll::Ready(()),
+        // woken again when the body goes away
+        _ => Poll::Pending,
+    }).await
+}
+
+// like /next, but the lease lasts exactly as long as this response keeps streaming: renewed with every line
+// (one json object per line, the first one as from /next) and released as soon as the client disconnects
+async fn get_hold (
+    Query(params): Query<NextParams>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    principal: Option<Extension<Principal>>,
+    State(state): State<Arc<Mutex<AppState<'static>>>>,
+) -> Response {
+    if params.callback.is_some() {
+        // the id goes back when the client disconnects, so there is never an expiry to call back about
+        return json_error(ERROR_CODE_INVALID_CALLBACK).into_response();
+    }
+    let (lease, json) = {
+        let mut state = state.lock().expect("Poisoned get_hold mutex");
+        let requester = Requester::new(addr.ip(), &headers, &state.trusted_proxies);
+        let principal = principal.map(|Extension(principal)| principal.name);
+        next_json(params.owner, params.label.as_deref(), requester, principal, &mut state)
+    };
+    let Some((id, mut expire)) = lease else {
+        return json.into_response();
+    };
+
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut line = json.0;
+        loop {
+            let sent = sender.send_data(format!("{}\n", line).into()).await;
+            if sent.is_err() || line.get("error").is_some() {
+                break;
+            }
+            let tick = {
+                let state = state.lock().expect("Poisoned get_hold mutex");
+                // often enough to survive a couple of slow ticks, but never throttled
+                (state.pool.timeout / 3).max(state.min_heartbeat_interval).max(1)
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_millis(tick as u64)) => {}
+                _ = body_closed(&mut sender) => break,
+            }
+
+            let mut state = state.lock().expect("Poisoned get_hold mutex");
+            line = match timed("hold", &mut state, |state| hold_tick_impl(id, expire, state)) {
+                Ok(renewed) => {
+                    expire = renewed;
+                    json_success(id, expire, state.pool.is_overflow(id)).0
+                }
+                Err(code) => json_error(code).0
+            };
         }
-    } else {
-        Err(ERROR_CODE_ID_NONEXISTENT)
+        // however it ended, unless it is no longer this lease (eg it already expired) or pinned
+        let mut state = state.lock().expect("Poisoned get_hold mutex");
+        release_impl(id, expire, &mut state);
+    });
+    ([(header::CONTENT_TYPE, "application/x-ndjson")], axum::body::boxed(body)).into_response()
+}
+
+fn json_stats_minute (stats: &StatsMinute) -> Value {
+    json!({
+        "minute": stats.minute,
+        "allocations": stats.allocations,
+        "expirations": stats.expirations,
+        "releases": stats.releases,
+        "leased": stats.leased,
+        "total": stats.total,
+        "utilization": if stats.total > 0 { stats.leased as f64 / stats.total as f64 } else { 0.0 },
+    })
+}
+
+// whether ids mostly come back by expiring (clients crashing or hanging) or by being released (clients shutting down cleanly)
+fn json_recycling (state: &MutexGuard<AppState>) -> Value {
+    let recycled = state.expirations_total + state.releases_total;
+    json!({
+        "expired_fraction": if recycled > 0 { Some(state.expirations_total as f64 / recycled as f64) } else { None },
+        "reclaim_passes": state.reclaim_passes.passes,
+        "reclaimed_per_pass": state.reclaim_passes.mean(),
+        "largest_reclaim_pass": state.reclaim_passes.largest,
+        "last_reclaim_pass": state.reclaim_passes.last,
+    })
+}
+
+// estimated bytes, for sizing MAX
+fn json_memory (state: &MutexGuard<AppState>) -> Value {
+    let expires = state.pool.expires.memory_bytes();
+    let leases = btree_bytes::<usize, Lease>(state.leases.len())
+        + state.leases.values().map(|lease| lease.owner.as_ref().map_or(0, String::capacity)).sum::<usize>();
+    let availables = state.pool.availables.memory_bytes() + state.pool.overflow_availables.memory_bytes();
+    let stats_history = state.stats_history.memory_bytes();
+    json!({
+        "expires": expires,
+        "leases": leases,
+        "availables": availables,
+        "stats_history": stats_history,
+        "total": expires + leases + availables + stats_history,
+    })
+}
+
+async fn get_stats (State(state): State<Arc<Mutex<AppState<'_>>>>) -> Json<Value> {
+    let mut state = state.lock().expect("Poisoned get_stats mutex");
+    refresh(&mut state);
+    let now = state.pool.now();
+    let leased = state.pool.leased();
+    let total = state.total();
+    Json(json!({
+        "total": total,
+        "leased": leased,
+        "available": state.pool.availables.len(),
+        "overflow_available": state.pool.overflow_availables.len(),
+        "reserved": state.reservations.len(),
+        "tickets_waiting": state.tickets_waiting.len(),
+        "utilization": if total > 0 { leased as f64 / total as f64 } else { 0.0 },
+        "allocations": state.allocations_total,
+        "expirations": state.expirations_total,
+        "releases": state.releases_total,
+        "recycling": json_recycling(&state),
+        "memory": json_memory(&state),
+        "self_check": state.self_check,
+        "quotas": state.quotas.quotas.0.keys()
+            .map(|principal| (principal.clone(), json!(state.quotas.status(principal, now))))
+            .collect::<serde_json::Map<_, _>>(),
+    }))
+}
+
+async fn get_stats_history (State(state): State<Arc<Mutex<AppState<'_>>>>) -> Json<Value> {
+    let mut state = state.lock().expect("Poisoned get_stats_history mutex");
+    refresh(&mut state);
+    Json(json!({
+        "minutes": state.stats_history.iter().map(json_stats_minute).collect::<Vec<_>>(),
+    }))
+}
+
+fn get_leases_top_impl (by: TopBy, n: usize, state: &mut MutexGuard<AppState>) -> Vec<(usize, Lease)> {
+    refresh(state);
+
+    let mut leases = state.leases.iter()
+        .map(|(&id, lease)| (id, lease.clone()))
+        .collect::<Vec<_>>();
+    match by {
+        TopBy::Age => leases.sort_by_key(|(id, lease)| (lease.allocated, *id)),
+        TopBy::Renewals => leases.sort_by_key(|(id, lease)| (std::cmp::Reverse(lease.renewals), *id)),
+    }
+    leases.truncate(n);
+    leases
+}
+
+fn json_lease (id: usize, lease: &Lease, state: &MutexGuard<AppState>) -> Value {
+    let now = state.pool.now();
+    json!({
+        "id": id,
+        "label": state.labels.label(id),
+        "exp": state.pool.expires.get(&id),
+        "owner": lease.owner,
+        "allocated": lease.allocated,
+        "age_ms": now - lease.allocated,
+        "renewals": lease.renewals,
+        "allocated_by": lease.allocated_by,
+        "renewed_by": lease.renewed_by,
+        "pinned": lease.pinned,
+    })
+}
+
+async fn get_leases_top (Query(params): Query<TopParams>, State(state): State<Arc<Mutex<AppState<'_>>>>) -> Json<Value> {
+    let mut state = state.lock().expect("Poisoned get_leases_top mutex");
+    let leases = timed("leases_top", &mut state, |state| get_leases_top_impl(params.by, params.n.unwrap_or(DEFAULT_TOP_N), state));
+    Json(json!({
+        "leases": leases.iter().map(|(id, lease)| json_lease(*id, lease, &state)).collect::<Vec<_>>(),
+    }))
+}
+
+type LeaseCounts = Vec<(String, usize)>;
+
+// most leases first
+fn count_leases_by (leases: &BTreeMap<usize, Lease>, key: impl Fn(&Lease) -> Option<String>) -> LeaseCounts {
+    let mut counts = BTreeMap::<String, usize>::new();
+    for lease in leases.values() {
+        if let Some(key) = key(lease) {
+            *counts.entry(key).or_default() += 1;
+        }
+    }
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    counts.sort_by_key(|(key, count)| (std::cmp::Reverse(*count), key.clone()));
+    counts
+}
+
+fn get_clients_impl (state: &mut MutexGuard<AppState>) -> (LeaseCounts, LeaseCounts) {
+    refresh(state);
+
+    let by_owner = count_leases_by(&state.leases, |lease| lease.owner.clone());
+    let by_addr = count_leases_by(&state.leases, |lease| {
+        lease.renewed_by.as_ref().or(lease.allocated_by.as_ref())
+            .map(|requester| requester.addr.clone())
+    });
+    (by_owner, by_addr)
+}
+
+async fn get_clients (State(state): State<Arc<Mutex<AppState<'_>>>>) -> Json<Value> {
+    let mut state = state.lock().expect("Poisoned get_clients mutex");
+    let (by_owner, by_addr) = timed("clients", &mut state, get_clients_impl);
+    Json(json!({
+        "by_owner": by_owner.iter().map(|(owner, leases)| json!({ "owner": owner, "leases": leases })).collect::<Vec<_>>(),
+        "by_addr": by_addr.iter().map(|(addr, leases)| json!({ "addr": addr, "leases": leases })).collect::<Vec<_>>(),
+    }))
+}
+
+async fn get_version () -> Json<Value> {
+    Json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_commit": env!("GIT_COMMIT"),
+        "build_timestamp": env!("BUILD_TIMESTAMP").parse::<i64>().unwrap_or_default(),
+        "features": env!("CARGO_FEATURES").split(',').filter(|feature| !feature.is_empty()).collect::<Vec<_>>(),
+    }))
+}
+
+async fn get_config (Extension(config): Extension<Arc<Config>>) -> Json<Value> {
+    Json(json!(*config))
+}
+
+fn check_anomalies (state: &mut MutexGuard<AppState>) -> Vec<Anomaly> {
+    refresh(state);
+
+    let now = state.pool.now();
+    let anomalies = find_anomalies(&state.leases, &state.expirations_by_owner.counts(), now, state.pool.timeout, &state.anomaly_thresholds);
+    state.expirations_by_owner.rotate();
+    let news = anomalies.iter()
+        .filter(|anomaly| !state.anomalies.contains(anomaly))
+        .cloned()
+        .collect::<Vec<_>>();
+    state.anomalies = anomalies;
+    state.anomalies_checked = now;
+    news
+}
+
+async fn get_leases_anomalies (State(state): State<Arc<Mutex<AppState<'_>>>>) -> Json<Value> {
+    let state = state.lock().expect("Poisoned get_leases_anomalies mutex");
+    Json(json!({
+        "checked": state.anomalies_checked,
+        "anomalies": state.anomalies,
+    }))
+}
+
+fn post_ticket_impl (principal: Option<&str>, state: &mut MutexGuard<AppState>) -> Result<usize, usize> {
+    check_quota(principal, state)?;
+    state.ticket_last += 1;
+    let ticket = state.ticket_last;
+    // counted when taken, since it will be bound to an id without the principal asking again
+    if let Some(principal) = principal {
+        let now = state.pool.now();
+        if state.quotas.record(principal, now).is_some() {
+            state.ticket_charges.insert(ticket, (principal.to_string(), now));
+        }
+    }
+    state.tickets.insert(ticket, None);
+    state.tickets_waiting.push(ticket, principal);
+    Ok(ticket)
+}
+
+fn get_ticket_impl (ticket: usize, state: &mut MutexGuard<AppState>) -> Result<TicketStatus, usize> {
+    refresh(state);
+
+    match state.tickets.get(&ticket) {
+        Some(&Some((id, expire))) => {
+            // tickets are delivered at most once, whether or not the lease is still alive
+            state.tickets.remove(&ticket);
+            if state.pool.expires.get(&id) == Some(&expire) {
+                Ok(TicketStatus::Bound(id, expire))
+            } else {
+                // the holder never picked up its id in time, so it went back into the pool
+                Err(ERROR_CODE_TICKET_EXPIRED)
+            }
+        }
+        Some(None) => {
+            let position = state.tickets_waiting.position(ticket)
+                .expect("Waiting ticket missing from queue");
+            Ok(TicketStatus::Waiting(position))
+        }
+        None => Err(ERROR_CODE_TICKET_NONEXISTENT)
+    }
+}
+
+// giving up on a ticket, so the id it is (or would be) bound to doesn't sit leased with nobody to heartbeat it,
+// with that id if it was already bound
+fn delete_ticket_impl (ticket: usize, state: &mut MutexGuard<AppState>) -> Result<Option<usize>, usize> {
+    refresh(state);
+
+    match state.tickets.remove(&ticket) {
+        Some(Some((id, expire))) => {
+            // straight to the next ticket in line, if any
+            let released = release_impl(id, expire, state);
+            bind_tickets(state);
+            Ok(released.then_some(id))
+        }
+        Some(None) => {
+            state.tickets_waiting.remove(ticket);
+            // it never got an id, so it never really counted
+            if let Some((principal, charged)) = state.ticket_charges.remove(&ticket) {
+                state.quotas.unrecord(&principal, charged);
+            }
+            Ok(None)
+        }
+        None => Err(ERROR_CODE_TICKET_NONEXISTENT)
+    }
+}
+
+fn json_ticket (ticket: usize, status: TicketStatus, state: &MutexGuard<AppState>) -> Json<Value> {
+    match status {
+        TicketStatus::Waiting(position) => Json(json!({
+            "ticket": ticket,
+            "position": position,
+        })),
+        TicketStatus::Bound(id, expire) => {
+            let mut json = json_success(id, expire, state.pool.is_overflow(id));
+            json.0["ticket"] = json!(ticket);
+            json
+        }
+    }
+}
+
+async fn post_ticket (principal: Option<Extension<Principal>>, State(state): State<Arc<Mutex<AppState<'_>>>>) -> Json<Value> {
+    let mut state = state.lock().expect("Poisoned post_ticket mutex");
+    let principal = principal.map(|Extension(principal)| principal.name);
+    let ticket = match post_ticket_impl(principal.as_deref(), &mut state) {
+        Ok(ticket) => ticket,
+        Err(_) => return json_quota_exceeded(principal.as_deref(), &state),
+    };
+    match timed("ticket", &mut state, |state| get_ticket_impl(ticket, state)) {
+        Ok(status) => {
+            let mut json = json_ticket(ticket, status, &state);
+            add_quota_remaining(&mut json, principal.as_deref(), &state);
+            json
+        }
+        Err(code) => json_error(code)
+    }
+}
+
+async fn get_ticket (Path(ticket): Path<usize>, State(state): State<Arc<Mutex<AppState<'_>>>>) -> Json<Value> {
+    let mut state = state.lock().expect("Poisoned get_ticket mutex");
+    match timed("ticket", &mut state, |state| get_ticket_impl(ticket, state)) {
+        Ok(status) => json_ticket(ticket, status, &state),
+        Err(code) => json_error(code)
+    }
+}
+
+async fn delete_ticket (Path(ticket): Path<usize>, State(state): State<Arc<Mutex<AppState<'_>>>>) -> Json<Value> {
+    let mut state = state.lock().expect("Poisoned delete_ticket mutex");
+    match timed("ticket", &mut state, |state| delete_ticket_impl(ticket, state)) {
+        Ok(released) => Json(json!({
+            "ticket": ticket,
+            "cancelled": true,
+            "released": released,
+        })),
+        Err(code) => json_error(code)
+    }
+}
+
+// for emergencies, eg a critical service that can't heartbeat during an incident but must not lose its id
+fn post_pin_impl (id: usize, by: Option<String>, state: &mut MutexGuard<AppState>) -> Result<Pin, usize> {
+    refresh(state);
+
+    if !state.pool.is_leased(id) {
+        return Err(ERROR_CODE_ID_NONEXISTENT);
+    }
+    let now = state.pool.now();
+    state.pool.expires.insert(id, i64::MAX);
+    let lease = state.leases.entry(id).or_insert(Lease { allocated: now, renewed: now, ..Lease::default() });
+    // pinning again keeps the original pin
+    Ok(lease.pinned.get_or_insert(Pin { at: now, by }).clone())
+}
+
+// back to a normal lease, as if just heartbeat
+fn delete_pin_impl (id: usize, state: &mut MutexGuard<AppState>) -> Result<i64, usize> {
+    let now = state.pool.now();
+    let Some(lease) = state.leases.get_mut(&id).filter(|lease| lease.pinned.is_some()) else {
+        return Err(ERROR_CODE_NOT_PINNED);
+    };
+    lease.pinned = None;
+    lease.renewed = now;
+    Ok(state.pool.lease(id, now))
+}
+
+async fn post_pin (Path(id): Path<usize>, principal: Option<Extension<Principal>>, State(state): State<Arc<Mutex<AppState<'_>>>>) -> Json<Value> {
+    let mut state = state.lock().expect("Poisoned post_pin mutex");
+    let by = principal.map(|Extension(principal)| principal.name);
+    match timed("pin", &mut state, |state| post_pin_impl(id, by.clone(), state)) {
+        Ok(pin) => {
+            logging::log(Severity::Notice, &json!({ "event": "lease_pinned", "id": id, "by": by }));
+            Json(json!({ "id": id, "pinned": pin }))
+        }
+        Err(code) => json_error(code)
+    }
+}
+
+async fn delete_pin (Path(id): Path<usize>, principal: Option<Extension<Principal>>, State(state): State<Arc<Mutex<AppState<'_>>>>) -> Json<Value> {
+    let mut state = state.lock().expect("Poisoned delete_pin mutex");
+    match timed("pin", &mut state, |state| delete_pin_impl(id, state)) {
+        Ok(expire) => {
+            let by = principal.map(|Extension(principal)| principal.name);
+            logging::log(Severity::Notice, &json!({ "event": "lease_unpinned", "id": id, "by": by }));
+            json_success(id, expire, state.pool.is_overflow(id))
+        }
+        Err(code) => json_error(code)
+    }
+}
+
+// the test clock only moves forward, like a real one, with everything due by then (expiries, reservations...) done at once
+fn set_clock_impl (to: i64, clock: &Arc<Mutex<FixedTimeProvider>>, state: &mut MutexGuard<AppState>) -> Result<i64, usize> {
+    if to < state.pool.now() {
+        return Err(ERROR_CODE_INVALID_TIME);
+    }
+    FixedTimeProvider::arc_set(clock, to);
+    activate_reservations(state);
+    // everything due by then, not just the one batch the reaper takes at a time
+    while state.pool.has_expired() {
+        clear_expired(state);
+    }
+    bind_tickets(state);
+    Ok(to)
+}
+
+async fn post_clock_advance (
+    Query(params): Query<ClockParams>,
+    Extension(clock): Extension<Arc<Mutex<FixedTimeProvider>>>,
+    State(state): State<Arc<Mutex<AppState<'_>>>>,
+) -> Json<Value> {
+    let mut state = state.lock().expect("Poisoned post_clock_advance mutex");
+    let result = state.pool.now().checked_add(params.ms)
+        .ok_or(ERROR_CODE_INVALID_TIME)
+        .and_then(|to| set_clock_impl(to, &clock, &mut state));
+    match result {
+        Ok(now) => Json(json!({ "now": now })),
+        Err(code) => json_error(code)
+    }
+}
+
+async fn post_clock_set (
+    Query(params): Query<ClockParams>,
+    Extension(clock): Extension<Arc<Mutex<FixedTimeProvider>>>,
+    State(state): State<Arc<Mutex<AppState<'_>>>>,
+) -> Json<Value> {
+    let mut state = state.lock().expect("Poisoned post_clock_set mutex");
+    match set_clock_impl(params.ms, &clock, &mut state) {
+        Ok(now) => Json(json!({ "now": now })),
+        Err(code) => json_error(code)
+    }
+}
+
+fn post_reserve_impl (at: i64, id: Option<usize>, principal: Option<&str>, state: &mut MutexGuard<AppState>) -> Result<usize, usize> {
+    // before 1970, or so far ahead its expire would overflow
+    if at < 0 || at.checked_add(state.pool.timeout).is_none() {
+        return Err(ERROR_CODE_INVALID_TIME);
     }
+    check_quota(principal, state)?;
+    refresh(state);
+
+    let id_reserved = state.pool.take(id)
+        .ok_or(if id.is_some() { ERROR_CODE_ID_UNAVAILABLE } else { ERROR_CODE_NO_ID_AVAILBLE })?;
+    state.reservations.insert(id_reserved, at);
+    if let Some(principal) = principal {
+        let now = state.pool.now();
+        state.quotas.record(principal, now);
+    }
+    // in case the time is already past
+    activate_reservations(state);
+    Ok(id_reserved)
 }
 
-async fn get_heartbeat (Path(id): Path<usize>, State(state): State<Arc<Mutex<AppState<'_>>>>) -> Json<Value> {
-    let state = state.lock().expect("Poisoned get_heartbeat mutex");
-    match get_heartbeat_impl(id, state) {
-        Ok(expire) => json_success(id, expire),
+async fn post_reserve (
+    Query(params): Query<ReserveParams>,
+    principal: Option<Extension<Principal>>,
+    State(state): State<Arc<Mutex<AppState<'_>>>>,
+) -> Json<Value> {
+    let mut state = state.lock().expect("Poisoned post_reserve mutex");
+    let principal = principal.map(|Extension(principal)| principal.name);
+    match timed("reserve", &mut state, |state| post_reserve_impl(params.at, params.id, principal.as_deref(), state)) {
+        Ok(id) => {
+            let mut json = json_success(id, params.at + state.pool.timeout, state.pool.is_overflow(id));
+            json.0["at"] = json!(params.at);
+            add_quota_remaining(&mut json, principal.as_deref(), &state);
+            json
+        }
+        Err(ERROR_CODE_QUOTA_EXCEEDED) => json_quota_exceeded(principal.as_deref(), &state),
         Err(code) => json_error(code)
     }
 }
 
+fn get_heartbeat_impl (id: usize, state: &mut MutexGuard<AppState>) -> Result<i64, usize> {
+    activate_reservations(state);
+    if state.reservations.contains_key(&id) {
+        // the reserving fleet is not supposed to be up yet
+        return Err(ERROR_CODE_ID_PENDING);
+    }
+    let now = state.pool.now();
+    if state.leases.get(&id).is_some_and(|lease| lease.pinned.is_some()) {
+        // a heartbeat would bring its expire back
+        return Ok(i64::MAX);
+    }
+    if state.pool.is_leased(id) {
+        if let Some(lease) = state.leases.get(&id) {
+            if now - lease.renewed < state.min_heartbeat_interval {
+                // not an error for the lease itself, which is still valid until its current expire
+                return Err(ERROR_CODE_HEARTBEAT_THROTTLED);
+            }
+        }
+    }
+    let expire = match state.pool.heartbeat(id) {
+        Ok(expire) => expire,
+        Err(HeartbeatError::Expired) => {
+            // the client kept using the id past its expire, so it may have been shared for a while (once it is reallocated)
+            let expire = state.pool.expires.get(&id).copied().unwrap_or(now);
+            let owner = state.leases.get(&id).and_then(|lease| lease.owner.clone());
+            state.reporter.report(&ErrorReport::new(
+                Level::Error,
+                "heartbeat_after_expiry",
+                format!("Heartbeat for id {} {}ms after it expired", id, now - expire),
+                json!({ "id": id, "owner": owner, "late_ms": now - expire }),
+            ));
+            return Err(ERROR_CODE_ID_EXPIRED);
+        }
+        Err(HeartbeatError::Nonexistent) => return Err(ERROR_CODE_ID_NONEXISTENT),
+    };
+    if let Some(lease) = state.leases.get_mut(&id) {
+        let interval = now - lease.renewed;
+        lease.renewed = now;
+        lease.renewals += 1;
+        state.heartbeat_cadence.record(interval);
+    }
+    Ok(expire)
+}
+
+async fn get_heartbeat (Path(id): Path<usize>, ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap, State(state): State<Arc<Mutex<AppState<'_>>>>) -> Json<Value> {
+    let mut state = state.lock().expect("Poisoned get_heartbeat mutex");
+    let requester = Requester::new(addr.ip(), &headers, &state.trusted_proxies);
+    let client = requester.addr.clone();
+    match timed("heartbeat", &mut state, |state| get_heartbeat_impl(id, state)) {
+        Ok(expire) => {
+            reset_backoff(&client, &mut state);
+            if let Some(lease) = state.leases.get_mut(&id) {
+                lease.renewed_by(requester);
+            }
+            json_success(id, expire, state.pool.is_overflow(id))
+        }
+        Err(code) => json_error_for(code, &client, &mut state)
+    }
+}
+
+#[cfg(feature = "sentry")]
+fn error_reporter (config: &Config) -> SharedReporter {
+    match config.sentry_dsn.clone() {
+        Some(dsn) => Arc::new(vec![Arc::new(LogReporter) as SharedReporter, Arc::new(reporter::SentryReporter::new(dsn))]),
+        None => Arc::new(LogReporter),
+    }
+}
+
+#[cfg(not(feature = "sentry"))]
+fn error_reporter (config: &Config) -> SharedReporter {
+    if config.sentry_dsn.is_some() {
+        panic!("SENTRY_DSN is set, but this build has no sentry feature");
+    }
+    Arc::new(LogReporter)
+}
 
 #[tokio::main]
 async fn main() {
-    let port = env_var_parse("PORT", DEFAULT_PORT);
-    let id_max = env_var_parse("MAX", DEFAULT_MAX);
-    let id_min = env_var_parse("MIN", DEFAULT_MIN);
-    let timeout = env_var_parse("TIMEOUT", DEFAULT_TIMEOUT);
-
-    let state = Arc::new(Mutex::new(AppState {
-        timeout,
-        expires: BTreeMap::new(),
-        availables: VecDeque::from((id_min..=id_max).collect::<Vec<usize>>()),
-        time_provider: &SYSTEM_TIME_PROVIDER,
+    let config = Config::from_env();
+    logging::init(&config.log).expect("Failed to connect to SYSLOG_ADDR");
+    if config.trusted_proxies.is_empty() {
+        // a reverse proxy on the same host would make every request it forwards look local
+        let groups = [("AUTH_LEASE", &config.auth_lease), ("AUTH_READ", &config.auth_read), ("AUTH_ADMIN", &config.auth_admin)];
+        for (name, policy) in groups.iter().filter(|(_, policy)| policy.uses(Mechanism::Localhost)) {
+            logging::log(Severity::Warning, &json!({ "event": "localhost_without_trusted_proxies", "group": name, "policy": policy.to_string() }));
+        }
+    }
+
+    let reporter = error_reporter(&config);
+    let panic_reporter = reporter.clone();
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        // eg a handler, which tokio turns into a dropped connection and a poisoned lock
+        panic_reporter.report(&ErrorReport::new(
+            Level::Fatal,
+            "panic",
+            info.to_string(),
+            json!({ "location": info.location().map(|location| location.to_string()) }),
+        ));
+        default_hook(info);
     }));
 
-    let app = Router::new()
+    let availables = if config.bitset_availables {
+        Availables::bitset(config.min..=config.max)
+    } else {
+        Availables::queue(config.min..=config.max)
+    };
+    let test_clock = config.test_clock.then(|| FixedTimeProvider::arc_new(SYSTEM_TIME_PROVIDER.unix_ts_ms()));
+    let time_provider: &'static (dyn TimeProvider + Send + Sync) = match test_clock.clone() {
+        Some(clock) => {
+            logging::log(Severity::Warning, &json!({ "event": "test_clock", "now": clock.unix_ts_ms() }));
+            // the pool holds it for as long as the process runs anyway
+            Box::leak(Box::new(clock))
+        }
+        None => &SYSTEM_TIME_PROVIDER,
+    };
+    let mut pool = Pool::new(config.timeout, availables, time_provider);
+    if let Some(overflow) = config.overflow.clone() {
+        pool = pool.with_overflow(overflow);
+    }
+    pool.expiry_batch = config.expiry_batch;
+    let mut state = AppState::new(pool);
+    state.maintenance_windows = config.maintenance_windows.clone();
+    state.min_heartbeat_interval = config.min_heartbeat_interval;
+    state.backoff_base = config.backoff_base;
+    state.backoff_max = config.backoff_max;
+    state.stats_history = StatsHistory::new(config.stats_history_hours * 60);
+    state.anomaly_thresholds = AnomalyThresholds {
+        age_factor: config.anomaly_age_factor,
+        expirations: config.anomaly_expirations,
+        address_changes: config.anomaly_address_changes,
+    };
+    state.trusted_proxies = config.trusted_proxies.clone();
+    state.quotas = QuotaTracker::new(config.quotas.clone());
+    state.labels = config.labels.clone();
+    if config.callbacks {
+        state.callbacks = Some(Arc::new(HttpCallbacks::default()));
+    }
+    state.tickets_waiting = Waiters::new(config.waiter_discipline, config.ticket_priorities.clone());
+    state.slow_operation_threshold = Duration::from_millis(config.slow_operation_threshold);
+    state.timeout_bounds = config.timeout_bounds.clone();
+    state.reporter = reporter.clone();
+    if let Some(seed_file) = config.seed_file.as_deref() {
+        let seeded = seed::load(seed_file)
+            .and_then(|seeds| seed_leases(seeds, &mut state))
+            .expect("Invalid SEED_FILE");
+        logging::log(Severity::Notice, &json!({ "event": "leases_seeded", "file": seed_file, "count": seeded }));
+    }
+    if config.self_check {
+        state.self_check = Some(SelfCheck::new(state.total()));
+    }
+    let state = Arc::new(Mutex::new(state));
+
+    let anomalies_state = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(config.anomaly_interval));
+        loop {
+            interval.tick().await;
+            let mut state = anomalies_state.lock().expect("Poisoned check_anomalies mutex");
+            for anomaly in timed("anomalies", &mut state, check_anomalies) {
+                let kind = json!(anomaly)["kind"].as_str().unwrap_or_default().to_string();
+                state.reporter.report(&ErrorReport::new(
+                    Level::Warning,
+                    "anomaly",
+                    format!("Lease anomaly: {}", kind),
+                    json!({ "anomaly": anomaly }),
+                ));
+            }
+        }
+    });
+
+    if config.self_check {
+        let self_check_state = state.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(config.self_check_interval));
+            loop {
+                interval.tick().await;
+                let mut state = self_check_state.lock().expect("Poisoned self_check mutex");
+                refresh(&mut state);
+                for violation in timed("self_check", &mut state, |state| self_check::run(state)) {
+                    state.reporter.report(&ErrorReport::new(
+                        Level::Error,
+                        "invariant_violation",
+                        violation.clone(),
+                        json!({ "violation": violation }),
+                    ));
+                }
+            }
+        });
+    }
+
+    // requests only clear one batch each, so this keeps up with the rest without anyone waiting on it
+    let reaper_state = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(config.reaper_interval));
+        loop {
+            interval.tick().await;
+            timed("tune_timeout", &mut reaper_state.lock().expect("Poisoned reaper mutex"), tune_timeout);
+            loop {
+                let more = {
+                    let mut state = reaper_state.lock().expect("Poisoned reaper mutex");
+                    timed("reaper", &mut state, refresh);
+                    state.pool.has_expired()
+                };
+                if !more {
+                    break;
+                }
+                // let waiting requests have the lock between batches
+                tokio::task::yield_now().await;
+            }
+        }
+    });
+
+    let api_keys = ApiKeys::load(&config.api_keys, config.api_keys_file.as_deref())
+        .expect("Invalid API_KEYS_FILE");
+    let api_keys = Arc::new(RwLock::new(api_keys));
+    if let Some(api_keys_file) = config.api_keys_file.clone() {
+        let api_keys = api_keys.clone();
+        let env_keys = config.api_keys.clone();
+        let reporter = reporter.clone();
+        tokio::spawn(async move {
+            let modified = |file: &str| std::fs::metadata(file).and_then(|metadata| metadata.modified()).ok();
+            let mut last_modified = modified(&api_keys_file);
+            let mut interval = tokio::time::interval(Duration::from_millis(config.api_keys_reload_interval));
+            loop {
+                interval.tick().await;
+                let current_modified = modified(&api_keys_file);
+                if current_modified == last_modified {
+                    continue;
+                }
+                last_modified = current_modified;
+                // a bad edit keeps the old keys working rather than locking everyone out
+                match ApiKeys::load(&env_keys, Some(&api_keys_file)) {
+                    Ok(loaded) => *api_keys.write().expect("Poisoned api keys lock") = loaded,
+                    Err(e) => reporter.report(&ErrorReport::new(
+                        Level::Error,
+                        "api_keys_reload_failed",
+                        format!("Failed to reload {}, keeping the previous keys", api_keys_file),
+                        json!({ "error": e }),
+                    )),
+                }
+            }
+        });
+    }
+    let auth_layer = |policy: &auth::AuthPolicy, role| AuthLayer {
+        policy: policy.clone(),
+        role,
+        api_keys: api_keys.clone(),
+        trusted_proxies: Arc::new(config.trusted_proxies.clone()),
+    };
+    if let Some(tcp_port) = config.tcp_port {
+        tokio::spawn(tcp::serve(tcp_port, auth_layer(&config.auth_lease, Role::Allocator), state.clone()));
+    }
+
+    let shedder = LoadShedder::new(config.max_in_flight, config.backoff_base);
+    // heartbeats are never shed, since a missed one can cost a client its id
+    let allocation_routes = Router::new()
         .route("/next", get(get_next))
+        .route("/hold", get(get_hold))
+        .route("/tickets", post(post_ticket))
+        .route("/reserve", post(post_reserve))
+        .route_layer(middleware::from_fn_with_state(shedder.clone(), shed_load));
+    let allocator_routes = Router::new()
+        .merge(allocation_routes)
         .route("/heartbeat/:id", get(get_heartbeat))
+        .route("/tickets/:ticket", get(get_ticket).delete(delete_ticket))
+        .route("/mine", get(get_mine))
+        .route_layer(middleware::from_fn_with_state(shedder, track_load))
+        .route_layer(middleware::from_fn_with_state(auth_layer(&config.auth_lease, Role::Allocator), require_auth));
+    let reader_routes = Router::new()
+        .route("/stats", get(get_stats))
+        .route("/stats/history", get(get_stats_history))
+        .route("/leases/top", get(get_leases_top))
+        .route("/leases/anomalies", get(get_leases_anomalies))
+        .route("/clients", get(get_clients))
+        .route("/timeout", get(get_timeout))
+        .route("/config", get(get_config))
+        .route("/version", get(get_version))
+        .route_layer(middleware::from_fn_with_state(auth_layer(&config.auth_read, Role::Reader), require_auth));
+
+    let mut admin_routes = Router::new()
+        .route("/admin/lease/:id/pin", post(post_pin).delete(delete_pin));
+    if let Some(clock) = test_clock {
+        admin_routes = admin_routes
+            .route("/debug/clock/advance", post(post_clock_advance))
+            .route("/debug/clock/set", post(post_clock_set))
+            .layer(Extension(clock));
+    }
+    let admin_routes = admin_routes
+        .route_layer(middleware::from_fn_with_state(auth_layer(&config.auth_admin, Role::Admin), require_auth));
+
+    let app = Router::new()
+        .merge(allocator_routes)
+        .merge(reader_routes)
+        .merge(admin_routes)
+        .layer(Extension(Arc::new(config.clone())))
         .with_state(state);
 
-    axum::Server::bind(&format!("0.0.0.0:{}", port).parse().unwrap())
-        .serve(app.into_make_service())
+    axum::Server::bind(&format!("0.0.0.0:{}", config.port).parse().unwrap())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
@@ -164,10 +1475,11 @@ async fn main() {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::VecDeque;
     use std::ops::Range;
 
     use crate::*;
-    use time_provider::{FixedTimeProvider, ZeroTimeProvider};
+    use sequential_id_generator::time_provider::{FixedTimeProvider, TimeProvider, ZeroTimeProvider};
 
     const TEST_TIMEOUT: i64 = 2000;
 
@@ -181,6 +1493,15 @@ mod tests {
         VecDeque::from(r.collect::<Vec<usize>>())
     }
 
+    #[derive(Default)]
+    struct RecordingReporter(Mutex<Vec<ErrorReport>>);
+
+    impl reporter::ErrorReporter for RecordingReporter {
+        fn report (&self, report: &ErrorReport) {
+            self.0.lock().unwrap().push(report.clone());
+        }
+    }
+
     #[test]
     fn get_next_impl_err () {
         let time_provider = FixedTimeProvider::new(123);
@@ -189,13 +1510,11 @@ mod tests {
             (1, now + TEST_TIMEOUT),
             (2, now + TEST_TIMEOUT),
         ]);
-        let state = Arc::new(Mutex::new(AppState {
-            timeout: TEST_TIMEOUT,
-            expires,
-            availables: availables_from_range(3..3),
-            time_provider: &time_provider,
-        }));
-        let result = get_next_impl(state.lock().unwrap());
+        let state = Arc::new(Mutex::new(AppState::new(Pool {
+            expires: expires.into(),
+            ..Pool::new(TEST_TIMEOUT, availables_from_range(3..3), &time_provider)
+        })));
+        let result = get_next_impl(None, &mut state.lock().unwrap());
         assert_eq!(result, Err(ERROR_CODE_NO_ID_AVAILBLE));
     }
 
@@ -207,13 +1526,11 @@ mod tests {
             (1, now + TEST_TIMEOUT),
             (2, now + TEST_TIMEOUT),
         ]);
-        let state = Arc::new(Mutex::new(AppState {
-            timeout: TEST_TIMEOUT,
-            expires,
-            availables: availables_from_range(3..4),
-            time_provider: &time_provider,
-        }));
-        let result = get_next_impl(state.lock().unwrap());
+        let state = Arc::new(Mutex::new(AppState::new(Pool {
+            expires: expires.into(),
+            ..Pool::new(TEST_TIMEOUT, availables_from_range(3..4), &time_provider)
+        })));
+        let result = get_next_impl(None, &mut state.lock().unwrap());
         assert_eq!(result, Ok((3, now + TEST_TIMEOUT)));
     }
 
@@ -226,12 +1543,10 @@ mod tests {
             (2, now + TEST_TIMEOUT),
         ]);
         let time_provider_state = time_provider.clone();
-        let state = Arc::new(Mutex::new(AppState {
-            timeout: TEST_TIMEOUT,
-            expires,
-            availables: availables_from_range(3..4),
-            time_provider: &time_provider_state,
-        }));
+        let state = Arc::new(Mutex::new(AppState::new(Pool {
+            expires: expires.into(),
+            ..Pool::new(TEST_TIMEOUT, availables_from_range(3..4), &time_provider_state)
+        })));
 
         {
             let result = clear_expired(&mut state.lock().unwrap());
@@ -239,38 +1554,503 @@ mod tests {
 
             // expires has removed the old entry
             let state = state.lock().unwrap();
-            assert_eq!(state.expires, vec_to_btree(vec![(2, now + TEST_TIMEOUT)]));
+            assert_eq!(state.pool.expires.iter().collect::<BTreeMap<_, _>>(), vec_to_btree(vec![(2, now + TEST_TIMEOUT)]));
             // and now the old id is at the end of the queue
-            assert_eq!(state.availables, VecDeque::from(vec![3,1]));
+            assert_eq!(state.pool.availables, VecDeque::from(vec![3,1]));
         }
 
         {
             FixedTimeProvider::arc_add(&time_provider, TEST_TIMEOUT / 2);
-            let result = get_next_impl(state.lock().unwrap());
+            let result = get_next_impl(None, &mut state.lock().unwrap());
             assert_eq!(result, Ok((3, now + TEST_TIMEOUT / 2 + TEST_TIMEOUT)));
-            let result2 = get_next_impl(state.lock().unwrap());
+            let result2 = get_next_impl(None, &mut state.lock().unwrap());
             assert_eq!(result2, Ok((1, now + TEST_TIMEOUT / 2 + TEST_TIMEOUT)));
-            let result3 = get_next_impl(state.lock().unwrap());
+            let result3 = get_next_impl(None, &mut state.lock().unwrap());
             assert_eq!(result3, Err(ERROR_CODE_NO_ID_AVAILBLE));
         }
 
         {
             FixedTimeProvider::arc_add(&time_provider, TEST_TIMEOUT / 2);
-            let result = get_next_impl(state.lock().unwrap());
+            let result = get_next_impl(None, &mut state.lock().unwrap());
             assert_eq!(result, Ok((2, now + TEST_TIMEOUT + TEST_TIMEOUT)));
         }
     }
 
     #[test]
-    fn get_heartbeat_impl_missing () {
-        let time_provider = ZeroTimeProvider {};
+    fn get_next_impl_expiry_batch () {
+        let time_provider = FixedTimeProvider::arc_new(123);
+        let now = time_provider.lock().unwrap().unix_ts_ms();
+        let expires = vec_to_btree(vec![
+            (1, now - TEST_TIMEOUT),
+            (2, now - TEST_TIMEOUT),
+            (3, now - TEST_TIMEOUT),
+        ]);
+        let time_provider_state = time_provider.clone();
+        let state = Arc::new(Mutex::new(AppState::new(Pool {
+            expires: expires.into(),
+            expiry_batch: 1,
+            ..Pool::new(TEST_TIMEOUT, availables_from_range(4..4), &time_provider_state)
+        })));
+
+        let mut state = state.lock().unwrap();
+        assert_eq!(get_next_impl(None, &mut state), Ok((1, now + TEST_TIMEOUT)));
+        // one batch was enough, so the rest are left for later
+        assert_eq!(state.pool.expires.len(), 3);
+        assert_eq!(get_next_impl(None, &mut state), Ok((2, now + TEST_TIMEOUT)));
+        assert_eq!(get_next_impl(None, &mut state), Ok((3, now + TEST_TIMEOUT)));
+        assert_eq!(get_next_impl(None, &mut state), Err(ERROR_CODE_NO_ID_AVAILBLE));
+    }
+
+    #[test]
+    fn get_next_impl_overflow () {
+        let time_provider = FixedTimeProvider::arc_new(123);
+        let now = time_provider.lock().unwrap().unix_ts_ms();
+        let time_provider_state = time_provider.clone();
+        let state = Arc::new(Mutex::new(
+            AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..2), &time_provider_state)
+                .with_overflow(100..=100))
+        ));
+
+        {
+            let mut state = state.lock().unwrap();
+            assert_eq!(get_next_impl(None, &mut state), Ok((1, now + TEST_TIMEOUT)));
+            assert!(!state.pool.is_overflow(1));
+            assert_eq!(get_next_impl(None, &mut state), Ok((100, now + TEST_TIMEOUT)));
+            assert!(state.pool.is_overflow(100));
+            assert_eq!(get_next_impl(None, &mut state), Err(ERROR_CODE_NO_ID_AVAILBLE));
+        }
+
+        {
+            // expired overflow ids go back to the overflow queue, and primary ids are preferred again
+            FixedTimeProvider::arc_add(&time_provider, TEST_TIMEOUT);
+            let mut state = state.lock().unwrap();
+            assert_eq!(clear_expired(&mut state), 2);
+            assert_eq!(state.pool.availables, VecDeque::from(vec![1]));
+            assert_eq!(state.pool.overflow_availables, VecDeque::from(vec![100]));
+            assert_eq!(get_next_impl(None, &mut state), Ok((1, now + TEST_TIMEOUT + TEST_TIMEOUT)));
+        }
+    }
+
+    #[test]
+    fn seed_leases_before_allocating () {
+        let time_provider = FixedTimeProvider::arc_new(0);
+        let time_provider_state = time_provider.clone();
+        let mut state = AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..4), &time_provider_state));
+        let seeds = seed::from_file_contents("2:legacy:5000\n3").unwrap();
+        assert_eq!(seed_leases(seeds.clone(), &mut state), Ok(2));
+        assert_eq!(state.leases[&2].owner.as_deref(), Some("legacy"));
+        assert_eq!(state.leases[&3].pinned, Some(Pin { at: 0, by: None }));
+        assert!(seed_leases(seeds, &mut state).is_err());
+
+        let state = Mutex::new(state);
+        let mut state = state.lock().unwrap();
+        assert_eq!(get_next_impl(None, &mut state), Ok((1, TEST_TIMEOUT)));
+        assert_eq!(get_next_impl(None, &mut state), Err(ERROR_CODE_NO_ID_AVAILBLE));
+        // seeded with an expire, so only lasting until then unless heartbeat
+        FixedTimeProvider::arc_add(&time_provider, 5000);
+        assert_eq!(get_next_impl(None, &mut state), Ok((1, 5000 + TEST_TIMEOUT)));
+        assert_eq!(get_next_impl(None, &mut state), Ok((2, 5000 + TEST_TIMEOUT)));
+        assert_eq!(get_heartbeat_impl(3, &mut state), Ok(i64::MAX));
+    }
+
+    #[test]
+    fn set_clock_impl_expires () {
+        let time_provider = FixedTimeProvider::arc_new(1000);
+        let time_provider_state = time_provider.clone();
+        let state = Arc::new(Mutex::new(AppState::new(Pool {
+            expiry_batch: 1,
+            ..Pool::new(TEST_TIMEOUT, availables_from_range(1..4), &time_provider_state)
+        })));
+
+        let mut state = state.lock().unwrap();
+        for id in 1..4 {
+            assert_eq!(get_next_impl(None, &mut state), Ok((id, 1000 + TEST_TIMEOUT)));
+        }
+        assert_eq!(set_clock_impl(999, &time_provider, &mut state), Err(ERROR_CODE_INVALID_TIME));
+        assert_eq!(set_clock_impl(1000 + TEST_TIMEOUT, &time_provider, &mut state), Ok(1000 + TEST_TIMEOUT));
+        // all already reclaimed, however small the batches, without waiting for the reaper
+        assert_eq!(state.pool.leased(), 0);
+        assert_eq!(state.expirations_total, 3);
+    }
+
+    #[test]
+    fn get_next_impl_label () {
+        let time_provider = FixedTimeProvider::arc_new(0);
+        let time_provider_state = time_provider.clone();
+        let state = Arc::new(Mutex::new(AppState {
+            labels: "gpu:3-4".parse().unwrap(),
+            ..AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..5), &time_provider_state))
+        }));
+
+        let mut state = state.lock().unwrap();
+        assert_eq!(get_next_impl(Some("gpu"), &mut state), Ok((3, TEST_TIMEOUT)));
+        assert_eq!(get_next_impl(None, &mut state), Ok((1, TEST_TIMEOUT)));
+        assert_eq!(get_next_impl(Some("gpu"), &mut state), Ok((4, TEST_TIMEOUT)));
+        // even with unlabeled ids left
+        assert_eq!(get_next_impl(Some("gpu"), &mut state), Err(ERROR_CODE_NO_ID_AVAILBLE));
+        assert_eq!(get_next_impl(Some("cpu"), &mut state), Err(ERROR_CODE_UNKNOWN_LABEL));
+        assert_eq!(json_lease(3, &state.leases[&3], &state)["label"], json!("gpu"));
+        FixedTimeProvider::arc_add(&time_provider, TEST_TIMEOUT);
+        assert_eq!(get_next_impl(Some("gpu"), &mut state), Ok((3, TEST_TIMEOUT * 2)));
+    }
+
+    #[test]
+    fn get_ticket_impl_bound_in_order () {
+        let time_provider = FixedTimeProvider::arc_new(123);
+        let now = time_provider.lock().unwrap().unix_ts_ms();
+        let expires = vec_to_btree(vec![
+            (1, now + TEST_TIMEOUT),
+            (2, now + TEST_TIMEOUT * 2),
+        ]);
+        let time_provider_state = time_provider.clone();
+        let state = Arc::new(Mutex::new(AppState::new(Pool {
+            expires: expires.into(),
+            ..Pool::new(TEST_TIMEOUT, availables_from_range(3..3), &time_provider_state)
+        })));
+
+        {
+            let mut state = state.lock().unwrap();
+            let ticket1 = post_ticket_impl(None, &mut state).unwrap();
+            let ticket2 = post_ticket_impl(None, &mut state).unwrap();
+            assert_eq!(get_ticket_impl(ticket1, &mut state), Ok(TicketStatus::Waiting(0)));
+            assert_eq!(get_ticket_impl(ticket2, &mut state), Ok(TicketStatus::Waiting(1)));
+        }
+
+        {
+            // the freed id goes to the first ticket, not to a plain /next
+            FixedTimeProvider::arc_add(&time_provider, TEST_TIMEOUT);
+            let mut state = state.lock().unwrap();
+            assert_eq!(get_next_impl(None, &mut state), Err(ERROR_CODE_NO_ID_AVAILBLE));
+            assert_eq!(get_ticket_impl(2, &mut state), Ok(TicketStatus::Waiting(0)));
+            assert_eq!(get_ticket_impl(1, &mut state), Ok(TicketStatus::Bound(1, now + TEST_TIMEOUT * 2)));
+            // and is only delivered once
+            assert_eq!(get_ticket_impl(1, &mut state), Err(ERROR_CODE_TICKET_NONEXISTENT));
+        }
+    }
+
+    #[test]
+    fn get_ticket_impl_expired () {
+        let time_provider = FixedTimeProvider::arc_new(123);
+        let time_provider_state = time_provider.clone();
+        let state = Arc::new(Mutex::new(
+            AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..2), &time_provider_state))
+        ));
+
+        let mut state = state.lock().unwrap();
+        let ticket = post_ticket_impl(None, &mut state).unwrap();
+        assert_eq!(bind_tickets(&mut state), 1);
+        FixedTimeProvider::arc_add(&time_provider, TEST_TIMEOUT);
+        assert_eq!(get_ticket_impl(ticket, &mut state), Err(ERROR_CODE_TICKET_EXPIRED));
+    }
+
+    #[test]
+    fn delete_ticket_impl_waiting_and_bound () {
+        let time_provider = FixedTimeProvider::arc_new(0);
+        let time_provider_state = time_provider.clone();
+        let state = Arc::new(Mutex::new(
+            AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..2), &time_provider_state))
+        ));
+
+        let mut state = state.lock().unwrap();
+        let ticket1 = post_ticket_impl(None, &mut state).unwrap();
+        let ticket2 = post_ticket_impl(None, &mut state).unwrap();
+        let ticket3 = post_ticket_impl(None, &mut state).unwrap();
+        assert_eq!(bind_tickets(&mut state), 1);
+        assert_eq!(delete_ticket_impl(ticket2, &mut state), Ok(None));
+        assert_eq!(get_ticket_impl(ticket3, &mut state), Ok(TicketStatus::Waiting(0)));
+        // the id bound to a cancelled ticket goes to the next one in line, rather than expiring unused
+        assert_eq!(delete_ticket_impl(ticket1, &mut state), Ok(Some(1)));
+        assert_eq!(get_ticket_impl(ticket3, &mut state), Ok(TicketStatus::Bound(1, TEST_TIMEOUT)));
+        assert_eq!(delete_ticket_impl(ticket1, &mut state), Err(ERROR_CODE_TICKET_NONEXISTENT));
+        assert_eq!(state.tickets_waiting.len(), 0);
+    }
+
+    #[test]
+    fn pin_impl_exempts_from_expiry () {
+        let time_provider = FixedTimeProvider::arc_new(0);
+        let time_provider_state = time_provider.clone();
+        let state = Arc::new(Mutex::new(
+            AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..3), &time_provider_state))
+        ));
+
+        let mut state = state.lock().unwrap();
+        assert_eq!(get_next_impl(None, &mut state), Ok((1, TEST_TIMEOUT)));
+        assert_eq!(post_pin_impl(2, None, &mut state), Err(ERROR_CODE_ID_NONEXISTENT));
+        assert_eq!(delete_pin_impl(1, &mut state), Err(ERROR_CODE_NOT_PINNED));
+        let pin = Pin { at: 0, by: Some("oncall".to_string()) };
+        assert_eq!(post_pin_impl(1, Some("oncall".to_string()), &mut state), Ok(pin.clone()));
+        FixedTimeProvider::arc_add(&time_provider, TEST_TIMEOUT * 10);
+        // pinning again keeps the original pin
+        assert_eq!(post_pin_impl(1, None, &mut state), Ok(pin));
+        assert_eq!(get_heartbeat_impl(1, &mut state), Ok(i64::MAX));
+        assert!(!release_impl(1, i64::MAX, &mut state));
+        assert_eq!(get_next_impl(None, &mut state), Ok((2, TEST_TIMEOUT * 11)));
+
+        assert_eq!(delete_pin_impl(1, &mut state), Ok(TEST_TIMEOUT * 11));
+        FixedTimeProvider::arc_add(&time_provider, TEST_TIMEOUT);
+        assert_eq!(get_heartbeat_impl(1, &mut state), Err(ERROR_CODE_ID_EXPIRED));
+    }
+
+    #[test]
+    fn post_ticket_impl_priority () {
+        let time_provider = FixedTimeProvider::arc_new(0);
+        let time_provider_state = time_provider.clone();
+        let state = Arc::new(Mutex::new(AppState {
+            tickets_waiting: Waiters::new(waiters::Discipline::Priority, "payments:10".parse().unwrap()),
+            ..AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..2), &time_provider_state))
+        }));
+
+        let mut state = state.lock().unwrap();
+        assert_eq!(get_next_impl(None, &mut state), Ok((1, TEST_TIMEOUT)));
+        let batch = post_ticket_impl(None, &mut state).unwrap();
+        let payments = post_ticket_impl(Some("payments"), &mut state).unwrap();
+        assert_eq!(get_ticket_impl(batch, &mut state), Ok(TicketStatus::Waiting(1)));
+        FixedTimeProvider::arc_add(&time_provider, TEST_TIMEOUT);
+        assert_eq!(get_ticket_impl(payments, &mut state), Ok(TicketStatus::Bound(1, TEST_TIMEOUT * 2)));
+        assert_eq!(get_ticket_impl(batch, &mut state), Ok(TicketStatus::Waiting(0)));
+    }
+
+    #[test]
+    fn quota_covers_tickets_and_reserve () {
+        let time_provider = FixedTimeProvider::arc_new(0);
+        let state = Arc::new(Mutex::new(AppState {
+            quotas: QuotaTracker::new("batch:2/hour".parse().unwrap()),
+            ..AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..4), &time_provider))
+        }));
+
+        let mut state = state.lock().unwrap();
+        let ticket = post_ticket_impl(Some("batch"), &mut state).unwrap();
+        assert_eq!(get_ticket_impl(ticket, &mut state), Ok(TicketStatus::Bound(1, TEST_TIMEOUT)));
+        assert_eq!(post_reserve_impl(TEST_TIMEOUT, None, Some("batch"), &mut state), Ok(2));
+        assert_eq!(post_ticket_impl(Some("batch"), &mut state), Err(ERROR_CODE_QUOTA_EXCEEDED));
+        assert_eq!(post_reserve_impl(TEST_TIMEOUT, None, Some("batch"), &mut state), Err(ERROR_CODE_QUOTA_EXCEEDED));
+        // a refused reservation takes nothing, and others are not held to it
+        assert_eq!(get_next_impl(None, &mut state), Ok((3, TEST_TIMEOUT)));
+        assert!(post_ticket_impl(Some("payments"), &mut state).is_ok());
+    }
+
+    #[test]
+    fn delete_ticket_impl_refunds_quota () {
+        let time_provider = FixedTimeProvider::arc_new(0);
+        let state = Arc::new(Mutex::new(AppState {
+            quotas: QuotaTracker::new("batch:2/hour".parse().unwrap()),
+            ..AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..2), &time_provider))
+        }));
+
+        let mut state = state.lock().unwrap();
+        let remaining = |state: &MutexGuard<AppState>| state.quotas.status("batch", 0).map(|status| status.remaining);
+        assert_eq!(get_next_impl(None, &mut state), Ok((1, TEST_TIMEOUT)));
+        let ticket = post_ticket_impl(Some("batch"), &mut state).unwrap();
+        assert_eq!(remaining(&state), Some(1));
+        // cancelled while still waiting, so it never got an id to count
+        assert_eq!(delete_ticket_impl(ticket, &mut state), Ok(None));
+        assert_eq!(remaining(&state), Some(2));
+        // but one that did keeps counting, even once cancelled
+        let ticket = post_ticket_impl(Some("batch"), &mut state).unwrap();
+        assert!(release_impl(1, TEST_TIMEOUT, &mut state));
+        assert_eq!(bind_tickets(&mut state), 1);
+        assert_eq!(delete_ticket_impl(ticket, &mut state), Ok(Some(1)));
+        assert_eq!(remaining(&state), Some(1));
+        assert!(state.ticket_charges.is_empty());
+    }
+
+    #[test]
+    fn post_reserve_impl_activates () {
+        let time_provider = FixedTimeProvider::arc_new(123);
+        let now = time_provider.lock().unwrap().unix_ts_ms();
+        let time_provider_state = time_provider.clone();
+        let state = Arc::new(Mutex::new(
+            AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..4), &time_provider_state))
+        ));
+        let at = now + TEST_TIMEOUT * 10;
+
+        {
+            let mut state = state.lock().unwrap();
+            assert_eq!(post_reserve_impl(at, Some(2), None, &mut state), Ok(2));
+            assert_eq!(post_reserve_impl(at, Some(2), None, &mut state), Err(ERROR_CODE_ID_UNAVAILABLE));
+            assert_eq!(post_reserve_impl(at, None, None, &mut state), Ok(1));
+            assert_eq!(post_reserve_impl(i64::MAX, None, None, &mut state), Err(ERROR_CODE_INVALID_TIME));
+            assert_eq!(post_reserve_impl(-1, None, None, &mut state), Err(ERROR_CODE_INVALID_TIME));
+            // reserved ids are not handed out in the meantime
+            assert_eq!(get_next_impl(None, &mut state), Ok((3, now + TEST_TIMEOUT)));
+            assert_eq!(get_heartbeat_impl(2, &mut state), Err(ERROR_CODE_ID_PENDING));
+        }
+
+        {
+            FixedTimeProvider::arc_set(&time_provider, at + TEST_TIMEOUT / 2);
+            let mut state = state.lock().unwrap();
+            assert_eq!(get_heartbeat_impl(2, &mut state), Ok(at + TEST_TIMEOUT / 2 + TEST_TIMEOUT));
+            // the one nobody heartbeat expires on the normal schedule, counting from its reserved time
+            FixedTimeProvider::arc_set(&time_provider, at + TEST_TIMEOUT);
+            assert_eq!(clear_expired(&mut state), 2);
+            // back in the order they expired
+            assert_eq!(state.pool.availables, VecDeque::from(vec![3, 1]));
+        }
+    }
+
+    #[test]
+    fn get_next_impl_maintenance () {
+        let time_provider = FixedTimeProvider::arc_new(0);
+        let time_provider_state = time_provider.clone();
+        let state = Arc::new(Mutex::new(AppState {
+            maintenance_windows: "00:00-01:00".parse().unwrap(),
+            ..AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..3), &time_provider_state))
+        }));
+
+        let mut state = state.lock().unwrap();
+        assert_eq!(get_next_impl(None, &mut state), Err(ERROR_CODE_MAINTENANCE));
+        let ticket = post_ticket_impl(None, &mut state).unwrap();
+        assert_eq!(get_ticket_impl(ticket, &mut state), Ok(TicketStatus::Waiting(0)));
+
+        FixedTimeProvider::arc_set(&time_provider, 60 * 60 * 1000);
+        assert_eq!(get_ticket_impl(ticket, &mut state), Ok(TicketStatus::Bound(1, 60 * 60 * 1000 + TEST_TIMEOUT)));
+        assert_eq!(get_next_impl(None, &mut state), Ok((2, 60 * 60 * 1000 + TEST_TIMEOUT)));
+    }
+
+    #[test]
+    fn record_backoff_exponential () {
+        let time_provider = FixedTimeProvider::arc_new(123);
+        let time_provider_state = time_provider.clone();
+        let state = Arc::new(Mutex::new(AppState {
+            backoff_base: 100,
+            backoff_max: 1000,
+            ..AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..1), &time_provider_state))
+        }));
+
+        let mut state = state.lock().unwrap();
+        assert_eq!(record_backoff("a", &mut state), 100);
+        assert_eq!(record_backoff("a", &mut state), 200);
+        assert_eq!(record_backoff("b", &mut state), 100);
+        assert_eq!(record_backoff("a", &mut state), 400);
+        assert_eq!(record_backoff("a", &mut state), 800);
+        assert_eq!(record_backoff("a", &mut state), 1000);
+        reset_backoff("a", &mut state);
+        assert_eq!(record_backoff("a", &mut state), 100);
+        // long quiet clients start over
+        FixedTimeProvider::arc_add(&time_provider, 2001);
+        assert_eq!(record_backoff("b", &mut state), 100);
+
+        let json = json_error_for(ERROR_CODE_NO_ID_AVAILBLE, "a", &mut state);
+        assert_eq!(json.0["error"]["backoff_ms"], json!(100));
+        let json = json_error_for(ERROR_CODE_ID_NONEXISTENT, "a", &mut state);
+        assert_eq!(json.0["error"].get("backoff_ms"), None);
+    }
+
+    #[test]
+    fn record_stats_history () {
+        let time_provider = FixedTimeProvider::arc_new(0);
+        let time_provider_state = time_provider.clone();
+        let state = Arc::new(Mutex::new(
+            AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..5), &time_provider_state))
+        ));
+
+        let mut state = state.lock().unwrap();
+        assert!(get_next_impl(None, &mut state).is_ok());
+        assert!(get_next_impl(None, &mut state).is_ok());
+        FixedTimeProvider::arc_set(&time_provider, 60_000);
+        let (id, expire) = get_next_impl(None, &mut state).unwrap();
+        assert!(release_impl(id, expire, &mut state));
+        assert_eq!(state.allocations_total, 3);
+        assert_eq!(state.expirations_total, 2);
+        assert_eq!(state.releases_total, 1);
+        assert_eq!(state.stats_history.iter().cloned().collect::<Vec<_>>(), vec![
+            StatsMinute { minute: 0, allocations: 2, expirations: 0, releases: 0, leased: 2, total: 4 },
+            StatsMinute { minute: 60_000, allocations: 1, expirations: 2, releases: 1, leased: 0, total: 4 },
+        ]);
+        let recycling = json_recycling(&state);
+        assert_eq!(recycling["reclaim_passes"], json!(1));
+        assert_eq!(recycling["reclaimed_per_pass"], json!(2.0));
+        assert_eq!(recycling["expired_fraction"], json!(2.0 / 3.0));
+    }
+
+    #[test]
+    fn get_leases_top_impl_sorted () {
+        let time_provider = FixedTimeProvider::arc_new(0);
+        let time_provider_state = time_provider.clone();
+        let state = Arc::new(Mutex::new(
+            AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..4), &time_provider_state))
+        ));
+
+        let mut state = state.lock().unwrap();
+        assert!(get_next_impl(None, &mut state).is_ok());
+        FixedTimeProvider::arc_add(&time_provider, 10);
+        assert!(get_next_impl(None, &mut state).is_ok());
+        assert!(get_next_impl(None, &mut state).is_ok());
+        FixedTimeProvider::arc_add(&time_provider, 10);
+        assert!(get_heartbeat_impl(3, &mut state).is_ok());
+
+        let ids = |leases: Vec<(usize, Lease)>| leases.into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+        assert_eq!(ids(get_leases_top_impl(TopBy::Age, 10, &mut state)), vec![1, 2, 3]);
+        assert_eq!(ids(get_leases_top_impl(TopBy::Renewals, 2, &mut state)), vec![3, 1]);
+    }
+
+    #[test]
+    fn check_anomalies_reports_new () {
+        let time_provider = FixedTimeProvider::arc_new(0);
+        let time_provider_state = time_provider.clone();
         let state = Arc::new(Mutex::new(AppState {
-            timeout: TEST_TIMEOUT,
-            expires: BTreeMap::new(),
-            availables: availables_from_range(1..3),
-            time_provider: &time_provider,
+            anomaly_thresholds: AnomalyThresholds { age_factor: 10, expirations: 2, address_changes: 2 },
+            ..AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..3), &time_provider_state))
         }));
-        let result = get_heartbeat_impl(1, state.lock().unwrap());
+
+        let mut state = state.lock().unwrap();
+        for _ in 0..2 {
+            let (id, _) = get_next_impl(None, &mut state).unwrap();
+            state.leases.get_mut(&id).unwrap().owner = Some("flaky".to_string());
+            FixedTimeProvider::arc_add(&time_provider, TEST_TIMEOUT);
+        }
+        let expected = vec![Anomaly::RepeatedExpiry { owner: "flaky".to_string(), expirations: 2 }];
+        assert_eq!(check_anomalies(&mut state), expected);
+        // only reported as an event the first time
+        assert_eq!(check_anomalies(&mut state), vec![]);
+        assert_eq!(state.anomalies, expected);
+        assert_eq!(state.anomalies_checked, TEST_TIMEOUT * 2);
+        // and forgotten once it stops letting leases expire
+        check_anomalies(&mut state);
+        assert_eq!(state.anomalies, vec![]);
+        assert!(state.expirations_by_owner.counts().is_empty());
+    }
+
+    #[test]
+    fn lease_renewed_by_address_changes () {
+        let requester = |addr: &str| Requester { addr: addr.to_string(), user_agent: None };
+        let mut lease = Lease { allocated_by: Some(requester("1.1.1.1")), ..Lease::default() };
+        lease.renewed_by(requester("1.1.1.1"));
+        assert_eq!(lease.address_changes, 0);
+        lease.renewed_by(requester("2.2.2.2"));
+        lease.renewed_by(requester("2.2.2.2"));
+        lease.renewed_by(requester("1.1.1.1"));
+        assert_eq!(lease.address_changes, 2);
+        assert_eq!(lease.allocated_by, Some(requester("1.1.1.1")));
+    }
+
+    #[test]
+    fn get_clients_impl_counts () {
+        let time_provider = ZeroTimeProvider {};
+        let state = Arc::new(Mutex::new(
+            AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..5), &time_provider))
+        ));
+
+        let mut state = state.lock().unwrap();
+        let requester = |addr: &str| Some(Requester { addr: addr.to_string(), user_agent: None });
+        for (owner, addr) in [(Some("a"), "1.1.1.1"), (Some("b"), "2.2.2.2"), (Some("b"), "1.1.1.1"), (None, "1.1.1.1")] {
+            let (id, _) = get_next_impl(None, &mut state).unwrap();
+            let lease = state.leases.get_mut(&id).unwrap();
+            lease.owner = owner.map(|owner| owner.to_string());
+            lease.allocated_by = requester(addr);
+        }
+        state.leases.get_mut(&4).unwrap().renewed_by(Requester { addr: "3.3.3.3".to_string(), user_agent: None });
+
+        let (by_owner, by_addr) = get_clients_impl(&mut state);
+        assert_eq!(by_owner, vec![("b".to_string(), 2), ("a".to_string(), 1)]);
+        assert_eq!(by_addr, vec![("1.1.1.1".to_string(), 2), ("2.2.2.2".to_string(), 1), ("3.3.3.3".to_string(), 1)]);
+    }
+
+    #[test]
+    fn get_heartbeat_impl_missing () {
+        let time_provider = ZeroTimeProvider {};
+        let state = Arc::new(Mutex::new(AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..3), &time_provider))));
+        let result = get_heartbeat_impl(1, &mut state.lock().unwrap());
         assert_eq!(result, Err(ERROR_CODE_ID_NONEXISTENT));
     }
 
@@ -283,14 +2063,34 @@ mod tests {
             (2, now + TEST_TIMEOUT),
         ]);
         time_provider.add(TEST_TIMEOUT / 2);
+        let state = Arc::new(Mutex::new(AppState::new(Pool {
+            expires: expires.into(),
+            ..Pool::new(TEST_TIMEOUT, availables_from_range(3..3), &time_provider)
+        })));
+        let result = get_heartbeat_impl(1, &mut state.lock().unwrap());
+        assert_eq!(result, Ok(now + TEST_TIMEOUT + TEST_TIMEOUT / 2));
+    }
+
+    #[test]
+    fn get_heartbeat_impl_throttled () {
+        let time_provider = FixedTimeProvider::arc_new(123);
+        let now = time_provider.lock().unwrap().unix_ts_ms();
+        let time_provider_state = time_provider.clone();
         let state = Arc::new(Mutex::new(AppState {
-            timeout: TEST_TIMEOUT,
-            expires,
-            availables: availables_from_range(3..3),
-            time_provider: &time_provider,
+            min_heartbeat_interval: TEST_TIMEOUT / 4,
+            ..AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..2), &time_provider_state))
         }));
-        let result = get_heartbeat_impl(1, state.lock().unwrap());
-        assert_eq!(result, Ok(now + TEST_TIMEOUT + TEST_TIMEOUT / 2));
+
+        let mut state = state.lock().unwrap();
+        assert_eq!(get_next_impl(None, &mut state), Ok((1, now + TEST_TIMEOUT)));
+        FixedTimeProvider::arc_add(&time_provider, TEST_TIMEOUT / 8);
+        assert_eq!(get_heartbeat_impl(1, &mut state), Err(ERROR_CODE_HEARTBEAT_THROTTLED));
+        // the interval counts from the last accepted heartbeat, not the throttled one
+        FixedTimeProvider::arc_add(&time_provider, TEST_TIMEOUT / 8);
+        assert_eq!(get_heartbeat_impl(1, &mut state), Ok(now + TEST_TIMEOUT / 4 + TEST_TIMEOUT));
+        FixedTimeProvider::arc_add(&time_provider, TEST_TIMEOUT / 8);
+        assert_eq!(get_heartbeat_impl(1, &mut state), Err(ERROR_CODE_HEARTBEAT_THROTTLED));
+        assert_eq!(state.pool.expires.get(&1), Some(&(now + TEST_TIMEOUT / 4 + TEST_TIMEOUT)));
     }
 
     #[test]
@@ -301,13 +2101,181 @@ mod tests {
             (1, now + TEST_TIMEOUT),
         ]);
         time_provider.add(TEST_TIMEOUT * 2);
+        let state = Arc::new(Mutex::new(AppState::new(Pool {
+            expires: expires.into(),
+            ..Pool::new(TEST_TIMEOUT, availables_from_range(2..3), &time_provider)
+        })));
+        let reports = Arc::new(RecordingReporter::default());
+        state.lock().unwrap().reporter = reports.clone();
+        let result = get_heartbeat_impl(1, &mut state.lock().unwrap());
+        assert_eq!(result, Err(ERROR_CODE_ID_EXPIRED));
+        let reports = reports.0.lock().unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].kind, "heartbeat_after_expiry");
+        assert_eq!(reports[0].extra["late_ms"], json!(TEST_TIMEOUT));
+    }
+
+    #[test]
+    fn slow_operation_threshold () {
+        let time_provider = ZeroTimeProvider {};
         let state = Arc::new(Mutex::new(AppState {
-            timeout: TEST_TIMEOUT,
-            expires,
-            availables: availables_from_range(2..3),
-            time_provider: &time_provider,
+            slow_operation_threshold: Duration::from_millis(10),
+            ..AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..5), &time_provider))
         }));
-        let result = get_heartbeat_impl(1, state.lock().unwrap());
-        assert_eq!(result, Err(ERROR_CODE_ID_EXPIRED));
+        let mut state = state.lock().unwrap();
+        assert_eq!(timed("next", &mut state, |state| get_next_impl(None, state)), Ok((1, TEST_TIMEOUT)));
+        assert_eq!(slow_operation("next", Duration::from_millis(9), &state), None);
+        let event = slow_operation("next", Duration::from_millis(10), &state).unwrap();
+        assert_eq!(event["operation"], "next");
+        assert_eq!(event["leased"], 1);
+        assert_eq!(event["total"], 4);
+    }
+
+    #[test]
+    fn tune_timeout_within_bounds () {
+        let time_provider = FixedTimeProvider::arc_new(0);
+        let time_provider_state = time_provider.clone();
+        let state = Arc::new(Mutex::new(
+            AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..2), &time_provider_state))
+        ));
+        let mut state = state.lock().unwrap();
+        assert_eq!(get_next_impl(None, &mut state), Ok((1, TEST_TIMEOUT)));
+        for _ in 0..cadence::MIN_SAMPLES {
+            FixedTimeProvider::arc_add(&time_provider, 100);
+            assert!(get_heartbeat_impl(1, &mut state).is_ok());
+        }
+        // recommends, but doesn't touch the timeout without bounds
+        assert_eq!(state.heartbeat_cadence.recommend().map(|recommendation| recommendation.recommended), Some(301));
+        assert_eq!(tune_timeout(&mut state), None);
+        state.timeout_bounds = Some(500..=5000);
+        assert_eq!(tune_timeout(&mut state), Some(500));
+        assert_eq!(state.pool.timeout, 500);
+    }
+
+    #[test]
+    fn get_mine_impl_by_owner_and_principal () {
+        let time_provider = FixedTimeProvider::arc_new(0);
+        let time_provider_state = time_provider.clone();
+        let state = Mutex::new(AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..5), &time_provider_state)));
+        let mut state = state.lock().unwrap();
+        let requester = || Requester { addr: "10.0.0.1".to_string(), user_agent: None };
+        for (owner, principal) in [("a", Some("team-1")), ("b", Some("team-1")), ("a", None)] {
+            let (lease, _) = next_json(Some(owner.to_string()), None, requester(), principal.map(str::to_string), &mut state);
+            assert!(lease.is_some());
+        }
+        assert_eq!(get_mine_impl(None, None, &mut state), Err(ERROR_CODE_NO_IDENTITY));
+        assert_eq!(get_mine_impl(Some("a"), None, &mut state), Ok(vec![1, 3]));
+        assert_eq!(get_mine_impl(None, Some("team-1"), &mut state), Ok(vec![1, 2]));
+        // an api key only ever sees its own, whatever owner it asks about
+        assert_eq!(get_mine_impl(Some("a"), Some("team-1"), &mut state), Ok(vec![1]));
+        assert_eq!(get_mine_impl(Some("a"), Some("team-2"), &mut state), Ok(vec![]));
+        FixedTimeProvider::arc_add(&time_provider, TEST_TIMEOUT);
+        assert_eq!(get_mine_impl(Some("a"), None, &mut state), Ok(vec![]));
+    }
+
+    #[derive(Default)]
+    struct RecordingCallbacks(Mutex<Vec<(String, Value)>>);
+
+    impl CallbackSender for RecordingCallbacks {
+        fn send (&self, url: &str, body: Value) {
+            self.0.lock().unwrap().push((url.to_string(), body));
+        }
+    }
+
+    #[test]
+    fn clear_expired_calls_back () {
+        let time_provider = FixedTimeProvider::arc_new(0);
+        let time_provider_state = time_provider.clone();
+        let callbacks = Arc::new(RecordingCallbacks::default());
+        let state = Mutex::new(AppState {
+            callbacks: Some(callbacks.clone()),
+            ..AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..3), &time_provider_state))
+        });
+        let mut state = state.lock().unwrap();
+        for _ in 0..2 {
+            let (id, _) = get_next_impl(None, &mut state).unwrap();
+            state.leases.get_mut(&id).unwrap().callback = Some(format!("http://supervisor/{}", id));
+        }
+        // handed back, so nobody needs telling
+        assert!(state.pool.expires.get(&2).copied().is_some_and(|expire| release_impl(2, expire, &mut state)));
+        FixedTimeProvider::arc_add(&time_provider, TEST_TIMEOUT);
+        assert_eq!(clear_expired(&mut state), 1);
+        let sent = callbacks.0.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, "http://supervisor/1");
+        assert_eq!(sent[0].1["event"], json!("lease_expired"));
+        assert_eq!(sent[0].1["id"], json!(1));
+    }
+
+    #[test]
+    fn hold_tick_and_release () {
+        let time_provider = FixedTimeProvider::arc_new(0);
+        let time_provider_state = time_provider.clone();
+        let state = Arc::new(Mutex::new(
+            AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..3), &time_provider_state))
+        ));
+        let mut state = state.lock().unwrap();
+        let (id, expire) = get_next_impl(None, &mut state).unwrap();
+        FixedTimeProvider::arc_add(&time_provider, TEST_TIMEOUT / 2);
+        let renewed = hold_tick_impl(id, expire, &mut state).unwrap();
+        // a stale expire means the lease is not the one held any more
+        assert_eq!(hold_tick_impl(id, expire, &mut state), Err(ERROR_CODE_ID_EXPIRED));
+        assert!(!release_impl(id, expire, &mut state));
+        assert!(release_impl(id, renewed, &mut state));
+        assert!(!state.leases.contains_key(&id));
+        assert_eq!(state.pool.availables, VecDeque::from(vec![2, 1]));
+    }
+
+    // pieces of valid input mixed with arbitrary characters, from a fixed seed so a failure reproduces
+    fn malformed_inputs (seed: u64, count: usize) -> impl Iterator<Item = String> {
+        const PIECES: [&str; 24] = [
+            ":", ",", "|", "+", "-", " ", "/", "@", "=", "\n", "0", "7", "23:59", "99:99", "-1", "18446744073709551616",
+            "mon", "api_key", "localhost", "allocator", "NEXT", "BEAT", "http://", "\u{1F600}",
+        ];
+        let mut state = seed;
+        let mut next = move || {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        (0..count).map(move |_| {
+            (0..next() % 12)
+                .map(|_| match next() % 4 {
+                    0 => char::from_u32((next() % 0x3000) as u32).unwrap_or('?').to_string(),
+                    _ => PIECES[(next() % PIECES.len() as u64) as usize].to_string(),
+                })
+                .collect()
+        })
+    }
+
+    #[test]
+    fn malformed_input_never_panics () {
+        let time_provider = ZeroTimeProvider {};
+        let state = Mutex::new(AppState::new(Pool::new(TEST_TIMEOUT, availables_from_range(1..3), &time_provider)));
+        let mut state = state.lock().unwrap();
+        let mut connection = tcp::Connection::new("10.0.0.1".parse().unwrap(), AuthLayer {
+            policy: "open".parse().unwrap(),
+            role: Role::Allocator,
+            api_keys: Arc::new(RwLock::new(ApiKeys::default())),
+            trusted_proxies: Arc::new(vec![]),
+        });
+        let trusted_proxies = ["10.0.0.1".parse().unwrap()];
+        for input in malformed_inputs(0x5eed, 20_000) {
+            let _ = input.parse::<auth::AuthPolicy>();
+            let _ = input.parse::<ApiKeys>();
+            let _ = input.parse::<MaintenanceWindows>();
+            let _ = input.parse::<quota::Quotas>();
+            let _ = input.parse::<logging::Facility>();
+            let _ = input.parse::<reporter::SentryDsn>();
+            let _ = connection.handle(&input, &mut state);
+            if let Ok(value) = input.parse() {
+                let headers = HeaderMap::from_iter([(axum::http::header::HeaderName::from_static("x-forwarded-for"), value)]);
+                let _ = Requester::new(trusted_proxies[0], &headers, &trusted_proxies);
+            }
+        }
+        connection.close(&mut state);
+        assert_eq!(state.pool.total(), 2);
     }
 }
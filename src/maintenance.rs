@@ -0,0 +1,160 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Serialize, Serializer};
+
+
+const MS_PER_MINUTE: i64 = 60_000;
+const MINUTES_PER_DAY: i64 = 24 * 60;
+// unix epoch day 0 was a thursday
+const EPOCH_WEEKDAY: i64 = 4;
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+// a recurring daily window (optionally only on one weekday), in UTC, eg "02:00-03:30" or "sun 23:00-01:00"
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaintenanceWindow {
+    pub weekday: Option<i64>,
+    pub start: i64,
+    pub end: i64,
+}
+
+impl MaintenanceWindow {
+    pub fn contains (&self, unix_ts_ms: i64) -> bool {
+        let minutes = unix_ts_ms.div_euclid(MS_PER_MINUTE);
+        let day = minutes.div_euclid(MINUTES_PER_DAY);
+        let minute = minutes.rem_euclid(MINUTES_PER_DAY);
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end && self.on_day(day)
+        } else {
+            // wraps past midnight, so the early minutes belong to the window that started the day before
+            (self.start <= minute && self.on_day(day)) || (minute < self.end && self.on_day(day - 1))
+        }
+    }
+
+    fn on_day (&self, day: i64) -> bool {
+        match self.weekday {
+            Some(weekday) => (day + EPOCH_WEEKDAY).rem_euclid(7) == weekday,
+            None => true
+        }
+    }
+}
+
+impl fmt::Display for MaintenanceWindow {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(weekday) = self.weekday {
+            write!(f, "{} ", WEEKDAYS[weekday as usize])?;
+        }
+        write!(f, "{:02}:{:02}-{:02}:{:02}", self.start / 60, self.start % 60, self.end / 60, self.end % 60)
+    }
+}
+
+fn parse_minute_of_day (s: &str) -> Result<i64, String> {
+    let (hours, minutes) = s.split_once(':')
+        .ok_or_else(|| format!("Expected HH:MM, got '{}'", s))?;
+    let hours = hours.parse::<i64>().map_err(|_| format!("Bad hours in '{}'", s))?;
+    let minutes = minutes.parse::<i64>().map_err(|_| format!("Bad minutes in '{}'", s))?;
+    if !(0..24).contains(&hours) || !(0..60).contains(&minutes) {
+        return Err(format!("Time out of range '{}'", s));
+    }
+    Ok(hours * 60 + minutes)
+}
+
+impl FromStr for MaintenanceWindow {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (weekday, range) = match s.split_once(' ') {
+            Some((weekday, range)) => {
+                let weekday = weekday.to_lowercase();
+                let weekday = WEEKDAYS.iter().position(|&w| w == weekday)
+                    .ok_or_else(|| format!("Unknown weekday '{}'", weekday))?;
+                (Some(weekday as i64), range.trim())
+            }
+            None => (None, s)
+        };
+        let (start, end) = range.split_once('-')
+            .ok_or_else(|| format!("Expected HH:MM-HH:MM, got '{}'", range))?;
+        Ok(Self {
+            weekday,
+            start: parse_minute_of_day(start)?,
+            end: parse_minute_of_day(end)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MaintenanceWindows(pub Vec<MaintenanceWindow>);
+
+impl MaintenanceWindows {
+    pub fn contains (&self, unix_ts_ms: i64) -> bool {
+        self.0.iter().any(|window| window.contains(unix_ts_ms))
+    }
+}
+
+// as the same strings they were configured with
+impl Serialize for MaintenanceWindows {
+    fn serialize<S: Serializer> (&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(|window| window.to_string()))
+    }
+}
+
+impl FromStr for MaintenanceWindows {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|window| !window.trim().is_empty())
+            .map(MaintenanceWindow::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .map(Self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS_PER_DAY: i64 = MINUTES_PER_DAY * MS_PER_MINUTE;
+    // 2023-11-12, a sunday
+    const SUNDAY: i64 = 19673 * MS_PER_DAY;
+
+    fn at (day: i64, hours: i64, minutes: i64) -> i64 {
+        day + (hours * 60 + minutes) * MS_PER_MINUTE
+    }
+
+    #[test]
+    fn parse () {
+        assert_eq!("02:00-03:30".parse(), Ok(MaintenanceWindow { weekday: None, start: 120, end: 210 }));
+        assert_eq!("Sun 23:00-01:00".parse(), Ok(MaintenanceWindow { weekday: Some(0), start: 1380, end: 60 }));
+        assert!("02:00".parse::<MaintenanceWindow>().is_err());
+        assert!("25:00-26:00".parse::<MaintenanceWindow>().is_err());
+        assert!("someday 02:00-03:00".parse::<MaintenanceWindow>().is_err());
+        assert_eq!("".parse(), Ok(MaintenanceWindows(vec![])));
+        assert_eq!("02:00-03:00, sat 04:00-05:00".parse::<MaintenanceWindows>().map(|w| w.0.len()), Ok(2));
+    }
+
+    #[test]
+    fn display () {
+        let windows: MaintenanceWindows = "02:00-03:30, Sun 23:00-01:05".parse().unwrap();
+        assert_eq!(serde_json::to_string(&windows).unwrap(), r#"["02:00-03:30","sun 23:00-01:05"]"#);
+    }
+
+    #[test]
+    fn contains_daily () {
+        let window: MaintenanceWindow = "02:00-03:30".parse().unwrap();
+        assert!(!window.contains(at(SUNDAY, 1, 59)));
+        assert!(window.contains(at(SUNDAY, 2, 0)));
+        assert!(window.contains(at(SUNDAY + MS_PER_DAY, 3, 29)));
+        assert!(!window.contains(at(SUNDAY, 3, 30)));
+    }
+
+    #[test]
+    fn contains_weekday_wrapping () {
+        let window: MaintenanceWindow = "sun 23:00-01:00".parse().unwrap();
+        assert!(window.contains(at(SUNDAY, 23, 30)));
+        assert!(window.contains(at(SUNDAY + MS_PER_DAY, 0, 30)));
+        assert!(!window.contains(at(SUNDAY, 0, 30)));
+        assert!(!window.contains(at(SUNDAY + MS_PER_DAY, 23, 30)));
+    }
+}
@@ -0,0 +1,13 @@
+use core::mem::size_of;
+
+
+// rough estimates of heap usage, for sizing MAX: exact figures depend on the allocator
+
+// nodes are at least half full, and usually about two thirds, plus their edges and lengths
+pub fn btree_bytes<K, V> (len: usize) -> usize {
+    len * (size_of::<K>() + size_of::<V>()) * 3 / 2
+}
+
+pub fn vec_bytes<T> (capacity: usize) -> usize {
+    capacity * size_of::<T>()
+}
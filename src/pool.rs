@@ -0,0 +1,301 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+use crate::availables::Availables;
+use crate::expires::Expires;
+use crate::time_provider::TimeProvider;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeartbeatError {
+    Expired,
+    Nonexistent,
+}
+
+// just the ids and their expiries, with everything else (leases, tickets, quotas...) layered on by the server
+pub struct Pool<'a> {
+    pub timeout: i64,
+    pub expires: Expires,
+    pub availables: Availables,
+    // secondary range, only handed out once availables is empty
+    pub overflow_range: Option<RangeInclusive<usize>>,
+    pub overflow_availables: Availables,
+    // most expired ids reclaimed per clear_expired, so a big backlog (eg after a pause) is spread over several calls
+    pub expiry_batch: usize,
+    pub time_provider: &'a(dyn TimeProvider + Send + Sync),
+}
+
+impl<'a> Pool<'a> {
+    pub fn new (timeout: i64, availables: impl Into<Availables>, time_provider: &'a(dyn TimeProvider + Send + Sync)) -> Self {
+        let availables = availables.into();
+        let mut expires = Expires::new();
+        if let Some(span) = availables.span() {
+            expires.add_range(span);
+        }
+        Self {
+            timeout,
+            expires,
+            availables,
+            overflow_range: None,
+            overflow_availables: Availables::default(),
+            expiry_batch: usize::MAX,
+            time_provider,
+        }
+    }
+
+    pub fn with_overflow (mut self, range: RangeInclusive<usize>) -> Self {
+        self.overflow_availables = self.availables.like(range.clone());
+        self.expires.add_range(range.clone());
+        self.overflow_range = Some(range);
+        self
+    }
+
+    pub fn now (&self) -> i64 {
+        self.time_provider.unix_ts_ms()
+    }
+
+    pub fn is_overflow (&self, id: usize) -> bool {
+        self.overflow_range.as_ref().is_some_and(|range| range.contains(&id))
+    }
+
+    // still within its timeout, as opposed to expired but not yet cleared
+    pub fn is_leased (&self, id: usize) -> bool {
+        self.expires.get(&id).is_some_and(|&expire| expire > self.now())
+    }
+
+    pub fn leased (&self) -> usize {
+        self.expires.len()
+    }
+
+    // every id in the pool is always exactly one of leased or available
+    pub fn total (&self) -> usize {
+        self.expires.len() + self.availables.len() + self.overflow_availables.len()
+    }
+
+    // puts an id back at the end of whichever queue it came from
+    pub fn release (&mut self, id: usize) {
+        if self.is_overflow(id) {
+            self.overflow_availables.push_back(id);
+        } else {
+            self.availables.push_back(id);
+        }
+    }
+
+    // whether clear_expired has anything (more) to do
+    pub fn has_expired (&self) -> bool {
+        self.expires.next_expire().is_some_and(|expire| expire <= self.now())
+    }
+
+    // handing back a lease early, with whether it was leased at all
+    pub fn free (&mut self, id: usize) -> bool {
+        let leased = self.expires.remove(&id).is_some();
+        if leased {
+            self.release(id);
+        }
+        leased
+    }
+
+    // the ids that went back to their queues, in the order they expired,
+    // never touching live leases, since the index is ordered by expiry and stops at the first one still live
+    pub fn clear_expired (&mut self) -> Vec<usize> {
+        let now = self.now();
+        let mut expireds = vec![];
+        while expireds.len() < self.expiry_batch {
+            let Some((id, _)) = self.expires.pop_expired(now) else {
+                break;
+            };
+            self.release(id);
+            expireds.push(id);
+        }
+        expireds
+    }
+
+    // out of the queues without leasing it, either a specific id or the next one (primary first)
+    pub fn take (&mut self, id: Option<usize>) -> Option<usize> {
+        if let Some(id) = id {
+            self.availables.remove(id)
+                .or_else(|| self.overflow_availables.remove(id))
+        } else {
+            self.availables.pop_front()
+                .or_else(|| self.overflow_availables.pop_front())
+        }
+    }
+
+    // the next id within any of the ranges (primary first), eg for one class of ids
+    pub fn take_in (&mut self, ranges: &[RangeInclusive<usize>]) -> Option<usize> {
+        self.availables.take_in(ranges)
+            .or_else(|| self.overflow_availables.take_in(ranges))
+    }
+
+    // an id already taken out of the queues, as if leased at the given time (never overflowing, eg for a far future reservation)
+    pub fn lease (&mut self, id: usize, at: i64) -> i64 {
+        let expire = at.saturating_add(self.timeout);
+        self.expires.renew(id, expire);
+        expire
+    }
+
+    pub fn allocate (&mut self) -> Option<(usize, i64)> {
+        let id_next = self.take(None)?;
+        let expire = self.lease(id_next, self.now());
+        Some((id_next, expire))
+    }
+
+    // what can never be true of a correct pool, eg an id both leased and available, for periodic self checks
+    // (walks and sorts every id, so only every so often)
+    pub fn invariant_violations (&self) -> Vec<String> {
+        let mut violations = vec![];
+        for id in self.availables.iter().filter(|&id| self.is_overflow(id)) {
+            violations.push(format!("overflow id {} is in the primary queue", id));
+        }
+        for id in self.overflow_availables.iter().filter(|&id| !self.is_overflow(id)) {
+            violations.push(format!("id {} is in the overflow queue", id));
+        }
+        let mut ids = self.expires.iter().map(|(id, _)| id)
+            .chain(self.availables.iter())
+            .chain(self.overflow_availables.iter())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        for pair in ids.windows(2).filter(|pair| pair[0] == pair[1]) {
+            let where_ = if self.expires.contains_key(&pair[0]) { "leased and available" } else { "available twice" };
+            violations.push(format!("id {} is {}", pair[0], where_));
+        }
+        violations
+    }
+
+    pub fn heartbeat (&mut self, id: usize) -> Result<i64, HeartbeatError> {
+        match self.expires.get(&id) {
+            Some(&expire) if expire > self.now() => Ok(self.lease(id, self.now())),
+            // Connecting client should take this error and request a new (next) id
+            Some(_) => Err(HeartbeatError::Expired),
+            None => Err(HeartbeatError::Nonexistent)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::collections::VecDeque;
+
+    use crate::time_provider::FixedTimeProvider;
+
+    const TEST_TIMEOUT: i64 = 2000;
+
+    #[test]
+    fn allocate_expire_reallocate () {
+        let time_provider = FixedTimeProvider::arc_new(123);
+        let time_provider_pool = time_provider.clone();
+        let mut pool = Pool::new(TEST_TIMEOUT, VecDeque::from(vec![1, 2]), &time_provider_pool);
+        assert_eq!(pool.allocate(), Some((1, 123 + TEST_TIMEOUT)));
+        assert_eq!(pool.allocate(), Some((2, 123 + TEST_TIMEOUT)));
+        assert_eq!(pool.allocate(), None);
+
+        FixedTimeProvider::arc_add(&time_provider, TEST_TIMEOUT / 2);
+        assert_eq!(pool.heartbeat(2), Ok(123 + TEST_TIMEOUT / 2 + TEST_TIMEOUT));
+        assert_eq!(pool.heartbeat(3), Err(HeartbeatError::Nonexistent));
+
+        FixedTimeProvider::arc_add(&time_provider, TEST_TIMEOUT / 2);
+        assert!(!pool.is_leased(1));
+        assert_eq!(pool.heartbeat(1), Err(HeartbeatError::Expired));
+        assert_eq!(pool.clear_expired(), vec![1]);
+        assert_eq!(pool.allocate(), Some((1, 123 + TEST_TIMEOUT * 2)));
+        assert_eq!(pool.total(), 2);
+    }
+
+    #[test]
+    fn clear_expired_in_batches () {
+        let time_provider = FixedTimeProvider::arc_new(0);
+        let time_provider_pool = time_provider.clone();
+        let mut pool = Pool {
+            expiry_batch: 2,
+            ..Pool::new(TEST_TIMEOUT, VecDeque::from(vec![1, 2, 3, 4, 5]), &time_provider_pool)
+        };
+        while pool.allocate().is_some() {}
+        FixedTimeProvider::arc_add(&time_provider, TEST_TIMEOUT);
+        assert!(pool.has_expired());
+        assert_eq!(pool.clear_expired(), vec![1, 2]);
+        assert_eq!(pool.clear_expired(), vec![3, 4]);
+        assert_eq!(pool.clear_expired(), vec![5]);
+        assert!(!pool.has_expired());
+    }
+
+    #[test]
+    fn take_specific_and_overflow () {
+        let time_provider = FixedTimeProvider::new(0);
+        let mut pool = Pool::new(TEST_TIMEOUT, VecDeque::from(vec![1, 2]), &time_provider)
+            .with_overflow(10..=11);
+        assert_eq!(pool.take(Some(11)), Some(11));
+        assert_eq!(pool.take(Some(11)), None);
+        assert_eq!(pool.take(None), Some(1));
+        assert_eq!(pool.take(None), Some(2));
+        assert_eq!(pool.take(None), Some(10));
+        pool.release(11);
+        pool.release(1);
+        assert_eq!(pool.availables, VecDeque::from(vec![1]));
+        assert_eq!(pool.overflow_availables, VecDeque::from(vec![11]));
+    }
+
+    #[test]
+    fn distant_overflow () {
+        let time_provider = FixedTimeProvider::new(0);
+        let mut pool = Pool::new(TEST_TIMEOUT, VecDeque::from(vec![1, 2]), &time_provider)
+            .with_overflow(100_000_000_000..=100_000_000_005);
+        assert_eq!(pool.allocate(), Some((1, TEST_TIMEOUT)));
+        assert_eq!(pool.allocate(), Some((2, TEST_TIMEOUT)));
+        assert_eq!(pool.allocate(), Some((100_000_000_000, TEST_TIMEOUT)));
+        assert!(pool.expires.memory_bytes() < 1024);
+        assert_eq!(pool.heartbeat(100_000_000_000), Ok(TEST_TIMEOUT));
+        assert!(pool.free(100_000_000_000));
+        assert_eq!(pool.total(), 8);
+    }
+
+    #[test]
+    fn take_in_ranges () {
+        let time_provider = FixedTimeProvider::new(0);
+        let mut pool = Pool::new(TEST_TIMEOUT, VecDeque::from(vec![1, 2, 3, 4]), &time_provider)
+            .with_overflow(10..=11);
+        assert_eq!(pool.take_in(&[3..=4, 11..=11]), Some(3));
+        assert_eq!(pool.take_in(&[3..=4, 11..=11]), Some(4));
+        assert_eq!(pool.take_in(&[3..=4, 11..=11]), Some(11));
+        assert_eq!(pool.take_in(&[3..=4, 11..=11]), None);
+        assert_eq!(pool.availables, VecDeque::from(vec![1, 2]));
+    }
+
+    #[test]
+    fn bitset_overflow () {
+        let time_provider = FixedTimeProvider::new(0);
+        let mut pool = Pool::new(TEST_TIMEOUT, Availables::bitset(1..=2), &time_provider)
+            .with_overflow(10..=11);
+        assert!(matches!(pool.overflow_availables, Availables::Bitset(_)));
+        assert_eq!(pool.take(Some(11)), Some(11));
+        assert_eq!(pool.allocate(), Some((1, TEST_TIMEOUT)));
+        pool.release(11);
+        assert_eq!(pool.total(), 4);
+        assert!(pool.free(1));
+        assert!(!pool.free(1));
+        assert_eq!(pool.availables, VecDeque::from(vec![2, 1]));
+    }
+
+    #[test]
+    fn invariant_violations () {
+        let time_provider = FixedTimeProvider::new(0);
+        let mut pool = Pool::new(TEST_TIMEOUT, VecDeque::from(vec![1, 2, 3]), &time_provider)
+            .with_overflow(10..=11);
+        pool.allocate();
+        assert!(pool.invariant_violations().is_empty());
+        // a release without the lease going away, like a double free would
+        pool.release(1);
+        pool.availables.push_back(3);
+        pool.availables.push_back(10);
+        assert_eq!(pool.invariant_violations(), vec![
+            "overflow id 10 is in the primary queue",
+            "id 1 is leased and available",
+            "id 3 is available twice",
+            "id 10 is available twice",
+        ]);
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Serialize, Serializer};
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuotaPeriod {
+    Hour,
+    Day,
+}
+
+impl QuotaPeriod {
+    fn ms (&self) -> i64 {
+        match self {
+            QuotaPeriod::Hour => 60 * 60 * 1000,
+            QuotaPeriod::Day => 24 * 60 * 60 * 1000,
+        }
+    }
+}
+
+// how many allocations a principal gets per period, which resets on the (UTC) hour or day, eg "100/hour"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub limit: usize,
+    pub period: QuotaPeriod,
+}
+
+impl Quota {
+    fn window (&self, unix_ts_ms: i64) -> i64 {
+        unix_ts_ms - unix_ts_ms.rem_euclid(self.period.ms())
+    }
+}
+
+impl FromStr for Quota {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Self, Self::Err> {
+        let (limit, period) = s.split_once('/')
+            .ok_or_else(|| format!("Expected count/period, got '{}'", s))?;
+        Ok(Self {
+            limit: limit.trim().parse().map_err(|_| format!("Bad count in '{}'", s))?,
+            period: match period.trim() {
+                "hour" => QuotaPeriod::Hour,
+                "day" => QuotaPeriod::Day,
+                _ => return Err(format!("Unknown period in '{}'", s)),
+            },
+        })
+    }
+}
+
+impl fmt::Display for Quota {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let period = match self.period {
+            QuotaPeriod::Hour => "hour",
+            QuotaPeriod::Day => "day",
+        };
+        write!(f, "{}/{}", self.limit, period)
+    }
+}
+
+impl Serialize for Quota {
+    fn serialize<S: Serializer> (&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+// principal -> quota, eg "batch:100/hour,ci:1000/day", where principals without one are unlimited
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct Quotas(pub BTreeMap<String, Quota>);
+
+impl FromStr for Quotas {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                let (principal, quota) = entry.split_once(':')
+                    .ok_or_else(|| format!("Expected principal:count/period, got '{}'", entry))?;
+                Ok((principal.trim().to_string(), quota.parse()?))
+            })
+            .collect::<Result<BTreeMap<_, _>, String>>()
+            .map(Self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct QuotaStatus {
+    pub limit: usize,
+    pub used: usize,
+    pub remaining: usize,
+    // unix ms when the current window ends
+    pub reset: i64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct QuotaTracker {
+    pub quotas: Quotas,
+    // principal -> (window start, allocations in it)
+    usage: BTreeMap<String, (i64, usize)>,
+}
+
+impl QuotaTracker {
+    pub fn new (quotas: Quotas) -> Self {
+        Self {
+            quotas,
+            usage: BTreeMap::new(),
+        }
+    }
+
+    pub fn status (&self, principal: &str, unix_ts_ms: i64) -> Option<QuotaStatus> {
+        let quota = self.quotas.0.get(principal)?;
+        let window = quota.window(unix_ts_ms);
+        let used = match self.usage.get(principal) {
+            // anything counted in an earlier window has been reset
+            Some(&(usage_window, used)) if usage_window == window => used,
+            _ => 0
+        };
+        Some(QuotaStatus {
+            limit: quota.limit,
+            used,
+            remaining: quota.limit.saturating_sub(used),
+            reset: window + quota.period.ms(),
+        })
+    }
+
+    // whether the principal can allocate another, with the status either way
+    pub fn check (&self, principal: &str, unix_ts_ms: i64) -> Result<Option<QuotaStatus>, QuotaStatus> {
+        match self.status(principal, unix_ts_ms) {
+            Some(status) if status.remaining == 0 => Err(status),
+            status => Ok(status)
+        }
+    }
+
+    pub fn record (&mut self, principal: &str, unix_ts_ms: i64) -> Option<QuotaStatus> {
+        let quota = self.quotas.0.get(principal)?;
+        let window = quota.window(unix_ts_ms);
+        let usage = self.usage.entry(principal.to_string()).or_insert((window, 0));
+        if usage.0 != window {
+            *usage = (window, 0);
+        }
+        usage.1 += 1;
+        self.status(principal, unix_ts_ms)
+    }
+
+    // gives back one recorded at the given time, eg for a ticket cancelled before it got an id,
+    // unless its window has since reset anyway
+    pub fn unrecord (&mut self, principal: &str, recorded_ts_ms: i64) {
+        let Some(quota) = self.quotas.0.get(principal) else {
+            return;
+        };
+        if let Some(usage) = self.usage.get_mut(principal) {
+            if usage.0 == quota.window(recorded_ts_ms) {
+                usage.1 = usage.1.saturating_sub(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: i64 = 60 * 60 * 1000;
+
+    #[test]
+    fn parse () {
+        let quotas: Quotas = "batch:2/hour, ci:1000/day".parse().unwrap();
+        assert_eq!(quotas.0.get("batch"), Some(&Quota { limit: 2, period: QuotaPeriod::Hour }));
+        assert_eq!(quotas.0.get("ci").map(|quota| quota.to_string()), Some("1000/day".to_string()));
+        assert!("batch:2/week".parse::<Quotas>().is_err());
+        assert!("batch".parse::<Quotas>().is_err());
+    }
+
+    #[test]
+    fn resets_on_schedule () {
+        let mut tracker = QuotaTracker::new("batch:2/hour".parse().unwrap());
+        let now = HOUR * 5 + 10;
+        assert_eq!(tracker.check("other", now), Ok(None));
+        assert_eq!(tracker.record("batch", now).map(|status| status.remaining), Some(1));
+        assert_eq!(tracker.record("batch", now + 1).map(|status| status.remaining), Some(0));
+        assert_eq!(tracker.check("batch", now + 2), Err(QuotaStatus { limit: 2, used: 2, remaining: 0, reset: HOUR * 6 }));
+        assert_eq!(tracker.check("batch", HOUR * 6).map(|status| status.map(|status| status.remaining)), Ok(Some(2)));
+        assert_eq!(tracker.record("batch", HOUR * 6).map(|status| status.used), Some(1));
+    }
+
+    #[test]
+    fn unrecord_within_window () {
+        let mut tracker = QuotaTracker::new("batch:2/hour".parse().unwrap());
+        tracker.record("batch", 10);
+        tracker.record("batch", 20);
+        tracker.unrecord("batch", 20);
+        assert_eq!(tracker.status("batch", 30).map(|status| status.remaining), Some(1));
+        // charged in an earlier window, which is already forgotten
+        tracker.record("batch", HOUR);
+        tracker.unrecord("batch", 10);
+        assert_eq!(tracker.status("batch", HOUR).map(|status| status.used), Some(1));
+        tracker.unrecord("other", 10);
+    }
+}
@@ -0,0 +1,84 @@
+use std::net::IpAddr;
+
+use axum::http::HeaderMap;
+use serde::Serialize;
+
+
+// who made a request, as best as can be told from the network
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Requester {
+    pub addr: String,
+    pub user_agent: Option<String>,
+}
+
+// the client address, trusting X-Forwarded-For only as far back as the chain of trusted proxies goes
+pub fn client_addr (peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+    if !trusted_proxies.contains(&peer) {
+        return peer;
+    }
+    let forwardeds = headers.get_all("x-forwarded-for").iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|addr| addr.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<_>>();
+    let mut client = peer;
+    for &addr in forwardeds.iter().rev() {
+        client = addr;
+        if !trusted_proxies.contains(&addr) {
+            break;
+        }
+    }
+    client
+}
+
+impl Requester {
+    pub fn new (peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> Self {
+        Self {
+            addr: client_addr(peer, headers, trusted_proxies).to_string(),
+            user_agent: headers.get("user-agent")
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip (s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn forwarded_for (s: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", s.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn client_addr_untrusted_peer () {
+        let headers = forwarded_for("1.1.1.1");
+        assert_eq!(client_addr(ip("9.9.9.9"), &headers, &[]), ip("9.9.9.9"));
+        assert_eq!(client_addr(ip("9.9.9.9"), &headers, &[ip("10.0.0.1")]), ip("9.9.9.9"));
+    }
+
+    #[test]
+    fn client_addr_trusted_chain () {
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+        // the spoofable leftmost entry is ignored once an untrusted hop is found
+        let headers = forwarded_for("6.6.6.6, 1.1.1.1, 10.0.0.2");
+        assert_eq!(client_addr(ip("10.0.0.1"), &headers, &trusted), ip("1.1.1.1"));
+        assert_eq!(client_addr(ip("10.0.0.1"), &HeaderMap::new(), &trusted), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn new_user_agent () {
+        let mut headers = HeaderMap::new();
+        headers.insert("user-agent", "worker/1.0".parse().unwrap());
+        assert_eq!(Requester::new(ip("1.1.1.1"), &headers, &[]), Requester {
+            addr: "1.1.1.1".to_string(),
+            user_agent: Some("worker/1.0".to_string()),
+        });
+    }
+}
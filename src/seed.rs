@@ -0,0 +1,52 @@
+use std::fs;
+
+
+// an id already in use before the service was, eg by a legacy process during a migration
+#[derive(Debug, Clone, PartialEq)]
+pub struct Seed {
+    pub id: usize,
+    pub owner: Option<String>,
+    // unix ms, or held until an admin unpins it when there is none
+    pub expire: Option<i64>,
+}
+
+// one `id:owner:expire` per line, where owner and expire can be left out (or empty), and # starts a comment,
+// eg "42:legacy-batch:1700000000000" or "43"
+pub fn from_file_contents (s: &str) -> Result<Vec<Seed>, String> {
+    s.lines()
+        .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|line| {
+            let mut fields = line.trim().splitn(3, ':').map(str::trim);
+            let id = fields.next().and_then(|id| id.parse().ok())
+                .ok_or_else(|| format!("Bad id in '{}'", line))?;
+            let owner = fields.next().filter(|owner| !owner.is_empty()).map(str::to_string);
+            let expire = match fields.next().filter(|expire| !expire.is_empty()) {
+                Some(expire) => Some(expire.parse().map_err(|_| format!("Bad expire in '{}'", line))?),
+                None => None,
+            };
+            Ok(Seed { id, owner, expire })
+        })
+        .collect()
+}
+
+pub fn load (file: &str) -> Result<Vec<Seed>, String> {
+    let contents = fs::read_to_string(file).map_err(|e| format!("Reading {}: {}", file, e))?;
+    from_file_contents(&contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse () {
+        let seeds = from_file_contents("# legacy fleet\n42:legacy-batch:1700000000000\n\n43\n44::1700000000000\n").unwrap();
+        assert_eq!(seeds, vec![
+            Seed { id: 42, owner: Some("legacy-batch".to_string()), expire: Some(1700000000000) },
+            Seed { id: 43, owner: None, expire: None },
+            Seed { id: 44, owner: None, expire: Some(1700000000000) },
+        ]);
+        assert!(from_file_contents("x:legacy").is_err());
+        assert!(from_file_contents("42:legacy:soon").is_err());
+    }
+}
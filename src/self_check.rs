@@ -0,0 +1,84 @@
+use serde::Serialize;
+
+use crate::AppState;
+
+
+// state corruption bugs are rare enough to go unnoticed until two clients share an id,
+// so this looks for them every so often instead
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SelfCheck {
+    // the pool size at startup, which every id being exactly one of leased, available or reserved keeps constant
+    pub expected_total: usize,
+    pub checks: usize,
+    pub checked: i64,
+    // summed over every check, so a scrape between two checks can't miss one
+    pub violations_total: usize,
+}
+
+impl SelfCheck {
+    pub fn new (expected_total: usize) -> Self {
+        Self { expected_total, ..Self::default() }
+    }
+}
+
+// the violations found, if checking at all
+pub fn run (state: &mut AppState) -> Vec<String> {
+    let Some(expected_total) = state.self_check.as_ref().map(|self_check| self_check.expected_total) else {
+        return vec![];
+    };
+    let now = state.pool.now();
+    let violations = violations(expected_total, state);
+    if let Some(self_check) = state.self_check.as_mut() {
+        self_check.checks += 1;
+        self_check.checked = now;
+        self_check.violations_total += violations.len();
+    }
+    violations
+}
+
+pub fn violations (expected_total: usize, state: &AppState) -> Vec<String> {
+    let mut violations = state.pool.invariant_violations();
+    if state.total() != expected_total {
+        violations.push(format!("{} ids accounted for instead of {}", state.total(), expected_total));
+    }
+    for (id, _) in state.reservations.iter() {
+        if state.pool.expires.contains_key(&id) || state.pool.availables.iter().chain(state.pool.overflow_availables.iter()).any(|available| available == id) {
+            violations.push(format!("reserved id {} is also in the pool", id));
+        }
+    }
+    for id in state.leases.keys().filter(|id| !state.pool.expires.contains_key(id)) {
+        violations.push(format!("lease details for id {} which is not leased", id));
+    }
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+
+    use sequential_id_generator::pool::Pool;
+    use sequential_id_generator::time_provider::ZeroTimeProvider;
+
+    use crate::Lease;
+
+    #[test]
+    fn consistent_then_corrupted () {
+        let time_provider = ZeroTimeProvider {};
+        let mut state = AppState::new(Pool::new(1000, (1..=4).collect::<VecDeque<_>>(), &time_provider));
+        state.pool.allocate();
+        state.reservations.insert(state.pool.take(None).unwrap(), 500);
+        assert_eq!(violations(4, &state), Vec::<String>::new());
+
+        state.pool.expires.remove(&1);
+        state.leases.insert(1, Lease::default());
+        state.pool.availables.push_back(2);
+        state.pool.availables.push_back(5);
+        assert_eq!(violations(4, &state), vec![
+            "5 ids accounted for instead of 4",
+            "reserved id 2 is also in the pool",
+            "lease details for id 1 which is not leased",
+        ]);
+    }
+}
@@ -0,0 +1,95 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use axum::{
+    extract::State,
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+
+use crate::{json_error, ERROR_CODE_OVERLOADED};
+
+
+// requests in flight on the lease routes, including any still waiting for the pool lock,
+// so allocations can be turned away before they make the heartbeats queue up behind them
+#[derive(Debug, Clone, Default)]
+pub struct LoadShedder {
+    in_flight: Arc<AtomicUsize>,
+    // 0 never sheds
+    pub max_in_flight: usize,
+    pub backoff_ms: i64,
+}
+
+// counts as in flight until dropped
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop (&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl LoadShedder {
+    pub fn new (max_in_flight: usize, backoff_ms: i64) -> Self {
+        Self {
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            max_in_flight,
+            backoff_ms,
+        }
+    }
+
+    pub fn enter (&self) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self.in_flight.clone())
+    }
+
+    pub fn in_flight (&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    // counting the request asking
+    pub fn overloaded (&self) -> bool {
+        self.max_in_flight > 0 && self.in_flight() > self.max_in_flight
+    }
+}
+
+// on every lease route, heartbeats included
+pub async fn track_load<B> (State(shedder): State<LoadShedder>, request: Request<B>, next: Next<B>) -> Response {
+    let _in_flight = shedder.enter();
+    next.run(request).await
+}
+
+// only on the routes that allocate, inside track_load
+pub async fn shed_load<B> (State(shedder): State<LoadShedder>, request: Request<B>, next: Next<B>) -> Response {
+    if !shedder.overloaded() {
+        return next.run(request).await;
+    }
+    let mut json = json_error(ERROR_CODE_OVERLOADED);
+    json.0["error"]["backoff_ms"] = json!(shedder.backoff_ms);
+    let retry_after = (shedder.backoff_ms.max(0) as u64).div_ceil(1000).to_string();
+    (StatusCode::SERVICE_UNAVAILABLE, [(header::RETRY_AFTER, retry_after)], json).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overloaded_while_in_flight () {
+        let shedder = LoadShedder::new(2, 100);
+        let first = shedder.enter();
+        let second = shedder.enter();
+        assert!(!shedder.overloaded());
+        let third = shedder.enter();
+        assert!(shedder.overloaded());
+        drop(first);
+        assert!(!shedder.overloaded());
+        drop((second, third));
+        assert_eq!(shedder.in_flight(), 0);
+        let unlimited = LoadShedder::new(0, 100);
+        let _in_flight = (unlimited.enter(), unlimited.enter());
+        assert!(!unlimited.overloaded());
+    }
+}
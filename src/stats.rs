@@ -0,0 +1,145 @@
+use std::collections::VecDeque;
+
+use serde::Serialize;
+
+
+const MS_PER_MINUTE: i64 = 60_000;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct StatsMinute {
+    // unix ms at the start of the minute
+    pub minute: i64,
+    pub allocations: usize,
+    pub expirations: usize,
+    // handed back early, as opposed to expiring
+    pub releases: usize,
+    // as of the last operation in the minute
+    pub leased: usize,
+    pub total: usize,
+}
+
+// the clear_expired passes that reclaimed anything (most find nothing), for how expired ids come back:
+// steady small passes are clients crashing now and then, rare big ones are whole fleets going away at once
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ReclaimPasses {
+    pub passes: usize,
+    pub reclaimed: usize,
+    pub largest: usize,
+    pub last: usize,
+}
+
+impl ReclaimPasses {
+    pub fn record (&mut self, reclaimed: usize) {
+        if reclaimed == 0 {
+            return;
+        }
+        self.passes += 1;
+        self.reclaimed += reclaimed;
+        self.largest = self.largest.max(reclaimed);
+        self.last = reclaimed;
+    }
+
+    pub fn mean (&self) -> Option<f64> {
+        (self.passes > 0).then(|| self.reclaimed as f64 / self.passes as f64)
+    }
+}
+
+// ring buffer of the most recent minutes, oldest first
+#[derive(Debug, Clone)]
+pub struct StatsHistory {
+    capacity: usize,
+    minutes: VecDeque<StatsMinute>,
+}
+
+impl StatsHistory {
+    pub fn new (capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            minutes: VecDeque::with_capacity(capacity.max(1)),
+        }
+    }
+
+    pub fn memory_bytes (&self) -> usize {
+        self.minutes.capacity() * std::mem::size_of::<StatsMinute>()
+    }
+
+    // the bucket for the minute containing unix_ts_ms, rolling in any quiet minutes since the last one
+    pub fn current (&mut self, unix_ts_ms: i64) -> &mut StatsMinute {
+        let minute = unix_ts_ms - unix_ts_ms.rem_euclid(MS_PER_MINUTE);
+        let last = self.minutes.back().cloned();
+        match last {
+            Some(last) if last.minute >= minute => {}
+            Some(last) => {
+                // quiet minutes carry the last known usage forward, but only as many as will fit
+                let skipped = ((minute - last.minute) / MS_PER_MINUTE) as usize;
+                let start = skipped.saturating_sub(self.capacity - 1).max(1);
+                for i in start..=skipped {
+                    self.push(StatsMinute {
+                        minute: last.minute + i as i64 * MS_PER_MINUTE,
+                        leased: last.leased,
+                        total: last.total,
+                        ..StatsMinute::default()
+                    });
+                }
+            }
+            None => {
+                self.push(StatsMinute {
+                    minute,
+                    ..StatsMinute::default()
+                });
+            }
+        }
+        self.minutes.back_mut().expect("Stats history always has a current minute")
+    }
+
+    fn push (&mut self, stats: StatsMinute) {
+        if self.minutes.len() >= self.capacity {
+            self.minutes.pop_front();
+        }
+        self.minutes.push_back(stats);
+    }
+
+    pub fn iter (&self) -> impl Iterator<Item = &StatsMinute> {
+        self.minutes.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn current_rolls_minutes () {
+        let mut history = StatsHistory::new(3);
+        history.current(10).allocations += 1;
+        history.current(MS_PER_MINUTE - 1).allocations += 1;
+        let current = history.current(MS_PER_MINUTE * 2 + 5);
+        current.expirations += 1;
+        current.leased = 4;
+        current.total = 10;
+        assert_eq!(history.iter().cloned().collect::<Vec<_>>(), vec![
+            StatsMinute { minute: 0, allocations: 2, ..StatsMinute::default() },
+            StatsMinute { minute: MS_PER_MINUTE, ..StatsMinute::default() },
+            StatsMinute { minute: MS_PER_MINUTE * 2, expirations: 1, leased: 4, total: 10, ..StatsMinute::default() },
+        ]);
+
+        // only the capacity is kept, with quiet minutes carrying usage forward
+        history.current(MS_PER_MINUTE * 100);
+        assert_eq!(history.iter().cloned().collect::<Vec<_>>(), vec![
+            StatsMinute { minute: MS_PER_MINUTE * 98, leased: 4, total: 10, ..StatsMinute::default() },
+            StatsMinute { minute: MS_PER_MINUTE * 99, leased: 4, total: 10, ..StatsMinute::default() },
+            StatsMinute { minute: MS_PER_MINUTE * 100, leased: 4, total: 10, ..StatsMinute::default() },
+        ]);
+    }
+
+    #[test]
+    fn reclaim_passes () {
+        let mut passes = ReclaimPasses::default();
+        assert_eq!(passes.mean(), None);
+        passes.record(3);
+        passes.record(0);
+        passes.record(1);
+        assert_eq!(passes, ReclaimPasses { passes: 2, reclaimed: 4, largest: 3, last: 1 });
+        assert_eq!(passes.mean(), Some(2.0));
+    }
+}
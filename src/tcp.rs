@@ -0,0 +1,244 @@
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use axum::http::HeaderMap;
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{AppState, ERROR_CODE_ID_EXPIRED, ERROR_CODE_ID_NONEXISTENT, ERROR_CODE_ID_PINNED, ERROR_CODE_MSGS, hold_tick_impl, next_json, release_impl};
+use crate::auth::{check_policy, AuthLayer, Principal};
+use crate::requester::Requester;
+
+
+// longer than any valid command, so a client can't make us buffer without limit
+const MAX_LINE: u64 = 256;
+const ERROR_CODE_UNKNOWN_COMMAND: usize = 0;
+
+// one line per command and per reply, for embedded clients and netcat:
+//   AUTH <key>  -> OK            (only needed when AUTH_LEASE wants an api key)
+//   NEXT [owner] -> OK <id> <exp>
+//   BEAT <id>   -> OK <id> <exp>
+//   FREE <id>   -> OK <id>       (or an error if it had already expired, or an admin pinned it)
+// with ERR <code> <msg> for errors, and every id the connection still holds released when it closes
+#[derive(Debug)]
+pub struct Connection {
+    peer: IpAddr,
+    auth: AuthLayer,
+    authorized: bool,
+    principal: Option<Principal>,
+    // id -> the expire last given to this connection
+    held: BTreeMap<usize, i64>,
+}
+
+fn error_line (code: usize) -> String {
+    let msg = ERROR_CODE_MSGS.get(&code).copied().unwrap_or("Unknown command!");
+    format!("ERR {} {}", code, msg)
+}
+
+fn json_line (json: &Value) -> String {
+    match json.get("error") {
+        Some(error) => format!("ERR {} {}", error["code"], error["msg"].as_str().unwrap_or_default()),
+        None => format!("OK {} {}", json["id"], json["exp"]),
+    }
+}
+
+impl Connection {
+    pub fn new (peer: IpAddr, auth: AuthLayer) -> Self {
+        let mut connection = Self {
+            peer,
+            auth,
+            authorized: false,
+            principal: None,
+            held: BTreeMap::new(),
+        };
+        // eg open or localhost policies need no AUTH at all
+        connection.authorized = connection.check(&HeaderMap::new()).is_ok();
+        connection
+    }
+
+    fn check (&mut self, headers: &HeaderMap) -> Result<(), usize> {
+        let api_keys = self.auth.api_keys.read().expect("Poisoned api keys lock");
+        self.principal = check_policy(&self.auth.policy, self.peer, headers, &api_keys, self.auth.role)?;
+        Ok(())
+    }
+
+    pub fn handle (&mut self, line: &str, state: &mut MutexGuard<AppState>) -> String {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default().to_uppercase();
+        let arg = words.next();
+        if command == "AUTH" {
+            let mut headers = HeaderMap::new();
+            if let Some(value) = arg.and_then(|key| key.parse().ok()) {
+                headers.insert("x-api-key", value);
+            }
+            return match self.check(&headers) {
+                Ok(()) => {
+                    self.authorized = true;
+                    "OK".to_string()
+                }
+                Err(code) => error_line(code)
+            };
+        }
+        if !self.authorized {
+            return match self.check(&HeaderMap::new()) {
+                Err(code) => error_line(code),
+                Ok(()) => {
+                    self.authorized = true;
+                    self.handle(line, state)
+                }
+            };
+        }
+        let id = arg.and_then(|id| id.parse::<usize>().ok());
+        match (command.as_str(), id) {
+            ("NEXT", _) => {
+                let requester = Requester { addr: self.peer.to_string(), user_agent: Some("tcp".to_string()) };
+                let principal = self.principal.as_ref().map(|principal| principal.name.clone());
+                let (lease, json) = next_json(arg.map(str::to_string), None, requester, principal, state);
+                if let Some((id, expire)) = lease {
+                    self.held.insert(id, expire);
+                }
+                json_line(&json.0)
+            }
+            ("BEAT", Some(id)) => {
+                let Some(&expire) = self.held.get(&id) else {
+                    return error_line(ERROR_CODE_ID_NONEXISTENT);
+                };
+                match hold_tick_impl(id, expire, state) {
+                    Ok(expire) => {
+                        self.held.insert(id, expire);
+                        format!("OK {} {}", id, expire)
+                    }
+                    Err(code) => {
+                        // still ours after a retryable error (eg throttled), so still released on close
+                        if code == ERROR_CODE_ID_EXPIRED || code == ERROR_CODE_ID_NONEXISTENT {
+                            self.held.remove(&id);
+                        }
+                        error_line(code)
+                    }
+                }
+            }
+            ("FREE", Some(id)) => match self.held.remove(&id) {
+                Some(expire) if release_impl(id, expire, state) => format!("OK {}", id),
+                // no longer this connection's to free either way
+                Some(_) if state.leases.get(&id).is_some_and(|lease| lease.pinned.is_some()) => error_line(ERROR_CODE_ID_PINNED),
+                Some(_) => error_line(ERROR_CODE_ID_EXPIRED),
+                None => error_line(ERROR_CODE_ID_NONEXISTENT)
+            },
+            _ => error_line(ERROR_CODE_UNKNOWN_COMMAND)
+        }
+    }
+
+    pub fn close (&mut self, state: &mut MutexGuard<AppState>) {
+        for (id, expire) in std::mem::take(&mut self.held) {
+            release_impl(id, expire, state);
+        }
+    }
+}
+
+async fn serve_connection (stream: TcpStream, peer: SocketAddr, auth: AuthLayer, state: Arc<Mutex<AppState<'static>>>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut connection = Connection::new(peer.ip(), auth);
+    let mut buf = vec![];
+    loop {
+        buf.clear();
+        match (&mut reader).take(MAX_LINE).read_until(b'\n', &mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) if buf.last() != Some(&b'\n') => break,
+            Ok(_) => {}
+        }
+        let reply = {
+            let mut state = state.lock().expect("Poisoned tcp mutex");
+            connection.handle(&String::from_utf8_lossy(&buf), &mut state)
+        };
+        if writer.write_all(format!("{}\n", reply).as_bytes()).await.is_err() {
+            break;
+        }
+    }
+    connection.close(&mut state.lock().expect("Poisoned tcp mutex"));
+}
+
+pub async fn serve (port: u16, auth: AuthLayer, state: Arc<Mutex<AppState<'static>>>) {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await.expect("Failed to bind TCP_PORT");
+    loop {
+        let Ok((stream, peer)) = listener.accept().await else {
+            continue;
+        };
+        tokio::spawn(serve_connection(stream, peer, auth.clone(), state.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::RwLock;
+
+    use sequential_id_generator::pool::Pool;
+    use sequential_id_generator::time_provider::ZeroTimeProvider;
+
+    use crate::auth::{ApiKeys, Role};
+    use crate::post_pin_impl;
+
+    fn connection (api_keys: &str) -> Connection {
+        Connection::new("10.0.0.1".parse().unwrap(), AuthLayer {
+            policy: if api_keys.is_empty() { "open" } else { "api_key" }.parse().unwrap(),
+            role: Role::Allocator,
+            api_keys: Arc::new(RwLock::new(api_keys.parse::<ApiKeys>().unwrap())),
+            trusted_proxies: Arc::new(vec![]),
+        })
+    }
+
+    #[test]
+    fn commands () {
+        let time_provider = ZeroTimeProvider {};
+        let state = Mutex::new(AppState::new(Pool::new(1000, (1..3).collect::<std::collections::VecDeque<_>>(), &time_provider)));
+        let mut state = state.lock().unwrap();
+        let mut connection = connection("");
+        assert_eq!(connection.handle("NEXT\n", &mut state), "OK 1 1000");
+        assert_eq!(connection.handle("next worker-a\n", &mut state), "OK 2 1000");
+        assert_eq!(state.leases.get(&2).and_then(|lease| lease.owner.clone()), Some("worker-a".to_string()));
+        assert_eq!(connection.handle("NEXT\n", &mut state), "ERR 1 No id available!");
+        assert_eq!(connection.handle("BEAT 1\n", &mut state), "OK 1 1000");
+        assert_eq!(connection.handle("FREE 1\n", &mut state), "OK 1");
+        // only its own ids
+        assert_eq!(connection.handle("BEAT 1\n", &mut state), "ERR 3 Id nonexistent!");
+        assert_eq!(connection.handle("BOGUS\n", &mut state), "ERR 0 Unknown command!");
+        // pinned by an admin in the meantime, so it stays leased rather than being freed
+        assert!(post_pin_impl(2, None, &mut state).is_ok());
+        assert_eq!(connection.handle("FREE 2\n", &mut state), "ERR 19 Id pinned!");
+        assert_eq!(connection.handle("FREE 2\n", &mut state), "ERR 3 Id nonexistent!");
+        connection.close(&mut state);
+        assert_eq!(state.pool.leased(), 1);
+    }
+
+    #[test]
+    fn throttled_beat_keeps_the_id () {
+        let time_provider = ZeroTimeProvider {};
+        let state = Mutex::new(AppState {
+            min_heartbeat_interval: 500,
+            ..AppState::new(Pool::new(1000, (1..3).collect::<std::collections::VecDeque<_>>(), &time_provider))
+        });
+        let mut state = state.lock().unwrap();
+        let mut connection = connection("");
+        assert_eq!(connection.handle("NEXT\n", &mut state), "OK 1 1000");
+        assert!(connection.handle("BEAT 1\n", &mut state).starts_with("ERR 9 "));
+        assert!(connection.handle("BEAT 1\n", &mut state).starts_with("ERR 9 "));
+        connection.close(&mut state);
+        assert_eq!(state.pool.leased(), 0);
+    }
+
+    #[test]
+    fn auth () {
+        let time_provider = ZeroTimeProvider {};
+        let state = Mutex::new(AppState::new(Pool::new(1000, (1..3).collect::<std::collections::VecDeque<_>>(), &time_provider)));
+        let mut state = state.lock().unwrap();
+        let mut connection = connection("reader:reader:abc,worker:allocator:def");
+        assert_eq!(connection.handle("NEXT\n", &mut state), "ERR 10 Unauthorized!");
+        assert_eq!(connection.handle("AUTH abc\n", &mut state), "ERR 11 Forbidden!");
+        assert_eq!(connection.handle("AUTH def\n", &mut state), "OK");
+        assert_eq!(connection.handle("NEXT\n", &mut state), "OK 1 1000");
+    }
+}
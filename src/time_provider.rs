@@ -1,4 +1,7 @@
 
+#[cfg(any(feature = "std", test))]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "std")]
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use dyn_clone::{clone_trait_object, DynClone};
@@ -10,6 +13,8 @@ pub trait TimeProvider : DynClone {
 
 clone_trait_object!(TimeProvider); // only needed for Box, not Arc?
 
+// without std there is no clock to read, so embedded users inject their own
+#[cfg(feature = "std")]
 #[derive(Debug, Clone)]
 pub struct SystemTimeProvider {
 }
@@ -17,6 +22,7 @@ pub struct SystemTimeProvider {
 // declare this and inject it everywhere in your real code paths, with FixedTimeProvider injected in tests:
 // static SYSTEM_TIME_PROVIDER: SystemTimeProvider = SystemTimeProvider {};
 
+#[cfg(feature = "std")]
 impl TimeProvider for SystemTimeProvider {
     fn unix_ts_ms (&self) -> i64 {
         let dur = SystemTime::now()
@@ -64,44 +70,39 @@ impl TimeProvider for ZeroTimeProvider {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    use std::sync::{Arc, Mutex};
-
-    // this is so we can change the contents of the time provider while state continues to hold it
-    impl TimeProvider for Arc<Mutex<FixedTimeProvider>> {
-        fn unix_ts_ms (&self) -> i64 {
-            self.lock().unwrap().fixed_unix_ts_ms
-        }
+#[cfg(any(feature = "std", test))]
+// this is so we can change the contents of the time provider while state continues to hold it
+impl TimeProvider for Arc<Mutex<FixedTimeProvider>> {
+    fn unix_ts_ms (&self) -> i64 {
+        self.lock().unwrap().fixed_unix_ts_ms
     }
+}
 
-    impl FixedTimeProvider {
-        pub fn arc_new (fixed_unix_ts_ms: i64) -> Arc<Mutex<Self>> {
-            Arc::new(Mutex::new(Self {
-                fixed_unix_ts_ms,
-            }))
-        }
-
-        pub fn arc_set (arc: &Arc<Mutex<Self>>, ms: i64) {
-            arc.lock().unwrap().fixed_unix_ts_ms = ms
-        }
+#[cfg(any(feature = "std", test))]
+impl FixedTimeProvider {
+    pub fn arc_new (fixed_unix_ts_ms: i64) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            fixed_unix_ts_ms,
+        }))
+    }
 
-        pub fn arc_add (arc: &Arc<Mutex<Self>>, ms: i64) {
-            arc.lock().unwrap().fixed_unix_ts_ms += ms
-        }
+    pub fn arc_set (arc: &Arc<Mutex<Self>>, ms: i64) {
+        arc.lock().unwrap().fixed_unix_ts_ms = ms
     }
 
-    /*
-    // in a test...
-    let time_provider = FixedTimeProvider::arc_new(123);
-    ...
-    let time_provider_injected = time_provider.clone();  // pass this to an object to own
-    ...
-    // assert before
-    FixedTimeProvider::arc_add(&time_provider, 1000);
-    // assert after
-    ... etc
-    */
+    pub fn arc_add (arc: &Arc<Mutex<Self>>, ms: i64) {
+        arc.lock().unwrap().fixed_unix_ts_ms += ms
+    }
 }
+
+/*
+// in a test...
+let time_provider = FixedTimeProvider::arc_new(123);
+...
+let time_provider_injected = time_provider.clone();  // pass this to an object to own
+...
+// assert before
+FixedTimeProvider::arc_add(&time_provider, 1000);
+// assert after
+... etc
+*/
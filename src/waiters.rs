@@ -0,0 +1,144 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::str::FromStr;
+
+use serde::Serialize;
+
+
+// which waiting ticket gets the next freed id
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Discipline {
+    // oldest first, so nobody waits forever
+    #[default]
+    Fifo,
+    // newest first, since the oldest waiters are the likeliest to have given up already
+    Lifo,
+    // highest priority principal first, oldest first within a priority (lower ones can wait forever under constant load)
+    Priority,
+}
+
+impl FromStr for Discipline {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "fifo" => Ok(Discipline::Fifo),
+            "lifo" => Ok(Discipline::Lifo),
+            "priority" => Ok(Discipline::Priority),
+            _ => Err(format!("Invalid discipline: {}", s)),
+        }
+    }
+}
+
+// principal -> priority, 0 for everyone not listed (including requests without an api key)
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Priorities(pub BTreeMap<String, i64>);
+
+impl FromStr for Priorities {
+    type Err = String;
+
+    // eg "payments:10,batch:-5"
+    fn from_str (s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|priority| !priority.trim().is_empty())
+            .map(|priority| {
+                let (principal, value) = priority.trim().split_once(':')
+                    .ok_or_else(|| format!("Invalid priority: {}", priority))?;
+                let value = value.parse::<i64>().map_err(|_| format!("Invalid priority: {}", priority))?;
+                Ok((principal.to_string(), value))
+            })
+            .collect::<Result<_, _>>()
+            .map(Priorities)
+    }
+}
+
+// waiting tickets, kept in the order they will be served
+#[derive(Debug, Clone, Default)]
+pub struct Waiters {
+    pub discipline: Discipline,
+    pub priorities: Priorities,
+    // (ticket, priority)
+    queue: VecDeque<(usize, i64)>,
+}
+
+impl Waiters {
+    pub fn new (discipline: Discipline, priorities: Priorities) -> Self {
+        Self { discipline, priorities, queue: VecDeque::new() }
+    }
+
+    pub fn len (&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn push (&mut self, ticket: usize, principal: Option<&str>) {
+        let priority = principal.and_then(|principal| self.priorities.0.get(principal)).copied().unwrap_or(0);
+        match self.discipline {
+            Discipline::Fifo => self.queue.push_back((ticket, priority)),
+            Discipline::Lifo => self.queue.push_front((ticket, priority)),
+            Discipline::Priority => {
+                // behind everyone of the same or higher priority
+                let index = self.queue.partition_point(|&(_, waiting)| waiting >= priority);
+                self.queue.insert(index, (ticket, priority));
+            }
+        }
+    }
+
+    pub fn front (&self) -> Option<usize> {
+        self.queue.front().map(|&(ticket, _)| ticket)
+    }
+
+    pub fn pop_front (&mut self) -> Option<usize> {
+        self.queue.pop_front().map(|(ticket, _)| ticket)
+    }
+
+    // how many will be served before it
+    pub fn position (&self, ticket: usize) -> Option<usize> {
+        self.queue.iter().position(|&(waiting, _)| waiting == ticket)
+    }
+
+    pub fn remove (&mut self, ticket: usize) -> bool {
+        let len = self.queue.len();
+        self.queue.retain(|&(waiting, _)| waiting != ticket);
+        self.queue.len() < len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn served (discipline: Discipline) -> Vec<usize> {
+        let mut waiters = Waiters::new(discipline, "payments:10,batch:-5".parse().unwrap());
+        for (ticket, principal) in [(1, Some("batch")), (2, None), (3, Some("payments")), (4, None), (5, Some("payments"))] {
+            waiters.push(ticket, principal);
+        }
+        std::iter::from_fn(|| waiters.pop_front()).collect()
+    }
+
+    #[test]
+    fn disciplines () {
+        assert_eq!(served(Discipline::Fifo), vec![1, 2, 3, 4, 5]);
+        assert_eq!(served(Discipline::Lifo), vec![5, 4, 3, 2, 1]);
+        assert_eq!(served(Discipline::Priority), vec![3, 5, 2, 4, 1]);
+    }
+
+    #[test]
+    fn position_and_remove () {
+        let mut waiters = Waiters::new(Discipline::Priority, "payments:10".parse().unwrap());
+        waiters.push(1, None);
+        waiters.push(2, Some("payments"));
+        assert_eq!(waiters.position(1), Some(1));
+        assert!(waiters.remove(2));
+        assert!(!waiters.remove(2));
+        assert_eq!(waiters.position(1), Some(0));
+    }
+
+    #[test]
+    fn parse () {
+        assert_eq!("lifo".parse::<Discipline>(), Ok(Discipline::Lifo));
+        assert!("random".parse::<Discipline>().is_err());
+        assert_eq!("".parse::<Priorities>(), Ok(Priorities::default()));
+        assert!("payments".parse::<Priorities>().is_err());
+        assert!("payments:high".parse::<Priorities>().is_err());
+    }
+}
@@ -0,0 +1,72 @@
+use alloc::boxed::Box;
+
+use crate::availables::Availables;
+use crate::pool::{HeartbeatError, Pool};
+use crate::time_provider::TimeProvider;
+
+
+// the host (eg a browser simulator) supplies the clock, as `env.unix_ts_ms` in the wasm imports
+#[link(wasm_import_module = "env")]
+extern "C" {
+    #[link_name = "unix_ts_ms"]
+    fn host_unix_ts_ms () -> f64;
+}
+
+#[derive(Debug, Clone)]
+pub struct HostTimeProvider {
+}
+
+impl TimeProvider for HostTimeProvider {
+    fn unix_ts_ms (&self) -> i64 {
+        unsafe { host_unix_ts_ms() as i64 }
+    }
+}
+
+static HOST_TIME_PROVIDER: HostTimeProvider = HostTimeProvider {};
+
+// exports for driving a pool from javascript (which holds the pointer from pool_new), where negative results are errors
+pub const RESULT_NO_ID_AVAILABLE: i64 = -1;
+pub const RESULT_ID_EXPIRED: i64 = -2;
+pub const RESULT_ID_NONEXISTENT: i64 = -3;
+
+#[no_mangle]
+pub extern "C" fn pool_new (min: usize, max: usize, timeout: i64) -> Box<Pool<'static>> {
+    Box::new(Pool::new(timeout, Availables::queue(min..=max), &HOST_TIME_PROVIDER))
+}
+
+#[no_mangle]
+pub extern "C" fn pool_free (pool: Box<Pool<'static>>) {
+    drop(pool);
+}
+
+#[no_mangle]
+pub extern "C" fn pool_clear_expired (pool: &mut Pool<'static>) -> usize {
+    pool.clear_expired().len()
+}
+
+// the id, with its expiry from pool_expire
+#[no_mangle]
+pub extern "C" fn pool_next (pool: &mut Pool<'static>) -> i64 {
+    pool.clear_expired();
+    pool.allocate().map_or(RESULT_NO_ID_AVAILABLE, |(id, _)| id as i64)
+}
+
+// the new expiry
+#[no_mangle]
+pub extern "C" fn pool_heartbeat (pool: &mut Pool<'static>, id: usize) -> i64 {
+    match pool.heartbeat(id) {
+        Ok(expire) => expire,
+        Err(HeartbeatError::Expired) => RESULT_ID_EXPIRED,
+        Err(HeartbeatError::Nonexistent) => RESULT_ID_NONEXISTENT,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn pool_expire (pool: &mut Pool<'static>, id: usize) -> i64 {
+    pool.expires.get(&id).copied().unwrap_or(RESULT_ID_NONEXISTENT)
+}
+
+#[no_mangle]
+pub extern "C" fn pool_leased (pool: &mut Pool<'static>) -> usize {
+    pool.leased()
+}
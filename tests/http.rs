@@ -0,0 +1,235 @@
+use std::collections::BTreeSet;
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Client, Request, StatusCode};
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use serde_json::{Value, json};
+
+
+// the real binary, as deployed, on a port of its own, killed when the test is done with it
+struct Server {
+    child: Child,
+    port: u16,
+    client: Client<HttpConnector>,
+}
+
+impl Server {
+    fn start (envs: &[(&str, &str)]) -> Self {
+        // free as of now, which is good enough for tests (the server has no PORT=0)
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let child = Command::new(env!("CARGO_BIN_EXE_sequential-id-generator"))
+            .env_clear()
+            .env("PORT", port.to_string())
+            .envs(envs.iter().copied())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start the server");
+        let started = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(started.elapsed() < Duration::from_secs(10), "Server never started listening");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        Self { child, port, client: Client::new() }
+    }
+
+    async fn request (&self, method: &str, path: &str, api_key: Option<&str>) -> (StatusCode, Value) {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(format!("http://127.0.0.1:{}{}", self.port, path));
+        if let Some(api_key) = api_key {
+            request = request.header("x-api-key", api_key);
+        }
+        let response = self.client.request(request.body(Body::empty()).unwrap()).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        // eg axum's own plain text rejections
+        let json = serde_json::from_slice(&body).unwrap_or_else(|_| json!(String::from_utf8_lossy(&body)));
+        (status, json)
+    }
+
+    async fn get (&self, path: &str) -> (StatusCode, Value) {
+        self.request("GET", path, None).await
+    }
+}
+
+impl Drop for Server {
+    fn drop (&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+const OPEN: [(&str, &str); 2] = [("AUTH_LEASE", "open"), ("AUTH_READ", "open")];
+
+#[tokio::test]
+async fn next_and_heartbeat () {
+    let server = Server::start(&[OPEN[0], OPEN[1], ("MIN", "1"), ("MAX", "2")]);
+
+    let (status, first) = server.get("/next").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(first["id"], json!(1));
+    assert!(first["exp"].as_i64().unwrap() > 0);
+    assert_eq!(server.get("/next?owner=worker-a").await.1["id"], json!(2));
+
+    // errors are still 200s, with a code and (when worth retrying) a backoff
+    let (status, exhausted) = server.get("/next").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(exhausted["error"]["code"], json!(1));
+    assert_eq!(exhausted["error"]["msg"], json!("No id available!"));
+    assert!(exhausted["error"]["backoff_ms"].as_i64().unwrap() > 0);
+
+    let (status, renewed) = server.get("/heartbeat/1").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(renewed["id"], json!(1));
+    assert!(renewed["exp"].as_i64().unwrap() >= first["exp"].as_i64().unwrap());
+    assert_eq!(server.get("/heartbeat/3").await.1["error"]["code"], json!(3));
+
+    let (_, stats) = server.get("/stats").await;
+    assert_eq!(stats["leased"], json!(2));
+}
+
+#[tokio::test]
+async fn routing_and_rejections () {
+    let server = Server::start(&OPEN);
+    assert_eq!(server.get("/nowhere").await.0, StatusCode::NOT_FOUND);
+    // only with a test clock
+    assert_eq!(server.request("POST", "/debug/clock/advance?ms=1", None).await.0, StatusCode::NOT_FOUND);
+    assert_eq!(server.get("/heartbeat/abc").await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(server.request("POST", "/next", None).await.0, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(server.get("/reserve").await.0, StatusCode::METHOD_NOT_ALLOWED);
+    let (status, version) = server.get("/version").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(version["version"], json!(env!("CARGO_PKG_VERSION")));
+}
+
+#[tokio::test]
+async fn api_key_auth () {
+    let server = Server::start(&[("API_KEYS", "worker:allocator:secret-w,dashboard:reader:secret-d")]);
+    let (status, body) = server.get("/next").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["error"]["code"], json!(10));
+    assert_eq!(server.request("GET", "/next", Some("wrong")).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(server.request("GET", "/next", Some("secret-d")).await.0, StatusCode::FORBIDDEN);
+    assert_eq!(server.request("GET", "/next", Some("secret-w")).await.1["id"], json!(1));
+    let (_, mine) = server.request("GET", "/mine", Some("secret-w")).await;
+    assert_eq!(mine["leases"][0]["id"], json!(1));
+    assert!(mine["leases"][0]["ttl_ms"].as_i64().unwrap() > 0);
+    // allocators can read too
+    assert_eq!(server.request("GET", "/stats", Some("secret-w")).await.0, StatusCode::OK);
+    assert_eq!(server.request("GET", "/stats", Some("secret-d")).await.1["leased"], json!(1));
+    // the config never gives away keys
+    let (_, config) = server.request("GET", "/config", Some("secret-d")).await;
+    assert!(!config.to_string().contains("secret-"));
+}
+
+#[tokio::test]
+async fn hold_releases_on_disconnect () {
+    let server = Server::start(&[OPEN[0], OPEN[1], ("TIMEOUT", "60000")]);
+    let uri = format!("http://127.0.0.1:{}/hold", server.port);
+    let mut response = server.client.get(uri.parse().unwrap()).await.unwrap();
+    let first = response.body_mut().data().await.unwrap().unwrap();
+    assert_eq!(serde_json::from_slice::<Value>(&first).unwrap()["id"], json!(1));
+    assert_eq!(server.get("/stats").await.1["leased"], json!(1));
+    drop(response);
+    // long before the next line (TIMEOUT / 3) would have found out
+    let dropped = Instant::now();
+    while server.get("/stats").await.1["leased"] != json!(0) {
+        assert!(dropped.elapsed() < Duration::from_secs(5), "Held id never released");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(server.get("/hold?callback=http://supervisor/").await.1["error"]["code"], json!(16));
+}
+
+#[tokio::test]
+async fn quota_covers_tickets_and_reservations () {
+    let server = Server::start(&[("API_KEYS", "batch:allocator:secret-b"), ("QUOTAS", "batch:2/hour")]);
+    let (_, ticket) = server.request("POST", "/tickets", Some("secret-b")).await;
+    assert_eq!(ticket["id"], json!(1));
+    assert_eq!(ticket["quota_remaining"], json!(1));
+    let (_, reserved) = server.request("POST", "/reserve?at=0", Some("secret-b")).await;
+    assert_eq!(reserved["quota_remaining"], json!(0));
+    for path in ["/tickets", "/reserve?at=0"] {
+        let (_, body) = server.request("POST", path, Some("secret-b")).await;
+        assert_eq!(body["error"]["code"], json!(12), "{}", path);
+        assert!(body["error"]["backoff_ms"].as_i64().unwrap() > 0);
+    }
+    assert_eq!(server.request("GET", "/next", Some("secret-b")).await.1["error"]["code"], json!(12));
+}
+
+#[tokio::test]
+async fn cancelled_ticket_refunds_quota () {
+    let server = Server::start(&[("API_KEYS", "batch:allocator:secret-b"), ("QUOTAS", "batch:2/hour"), ("MIN", "1"), ("MAX", "1")]);
+    assert_eq!(server.request("GET", "/next", Some("secret-b")).await.1["quota_remaining"], json!(1));
+    let (_, ticket) = server.request("POST", "/tickets", Some("secret-b")).await;
+    assert_eq!(ticket["quota_remaining"], json!(0));
+    let path = format!("/tickets/{}", ticket["ticket"]);
+    assert_eq!(server.request("DELETE", &path, Some("secret-b")).await.1["cancelled"], json!(true));
+    // back where it was before the ticket, so there is room for another
+    assert_eq!(server.request("POST", "/tickets", Some("secret-b")).await.1["quota_remaining"], json!(0));
+}
+
+#[tokio::test]
+async fn concurrent_allocations_are_unique () {
+    const CLIENTS: usize = 200;
+    let server = Server::start(&[OPEN[0], OPEN[1], ("MIN", "1"), ("MAX", "200")]);
+    let nexts = (0..CLIENTS)
+        .map(|_| {
+            let (client, uri) = (server.client.clone(), format!("http://127.0.0.1:{}/next", server.port));
+            tokio::spawn(async move {
+                let response = client.get(uri.parse().unwrap()).await.unwrap();
+                serde_json::from_slice::<Value>(&hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap()
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut ids = BTreeSet::new();
+    for next in nexts {
+        let id = next.await.unwrap()["id"].as_u64().expect("Every client should get an id");
+        assert!(ids.insert(id as usize), "Id {} handed out twice", id);
+    }
+    assert_eq!(ids, (1..=CLIENTS).collect());
+    assert_eq!(server.get("/next").await.1["error"]["code"], json!(1));
+}
+
+#[tokio::test]
+async fn malformed_paths_and_queries () {
+    let server = Server::start(&OPEN);
+    let long = format!("/heartbeat/{}", "9".repeat(10_000));
+    let requests = [
+        ("GET", "/heartbeat/99999999999999999999999"),
+        ("GET", "/heartbeat/-1"),
+        ("GET", "/heartbeat/%00"),
+        ("GET", long.as_str()),
+        ("GET", "/next?owner=%FF%FE"),
+        ("GET", "/next?owner=a&owner=b"),
+        ("GET", "/leases/top?n=-5&by=sideways"),
+        ("GET", "/leases/top?n=18446744073709551616"),
+        ("POST", "/reserve?at=soon"),
+        ("POST", "/reserve?at=9223372036854775807"),
+        ("POST", "/reserve?at=-9223372036854775808&id=0"),
+        ("GET", "/tickets/%2e%2e"),
+        ("GET", "/stats/%2F%2F/history"),
+    ];
+    for (method, path) in requests {
+        let (status, body) = server.request(method, path, None).await;
+        assert!(!status.is_server_error(), "{} {} -> {} {}", method, path, status, body);
+    }
+    // and nothing in there poisoned the pool lock
+    assert!(server.get("/next").await.1["id"].is_u64());
+}
+
+#[tokio::test]
+async fn test_clock_drives_expiry () {
+    let server = Server::start(&[OPEN[0], OPEN[1], ("AUTH_ADMIN", "open"), ("TEST_CLOCK", "true"), ("TIMEOUT", "60000")]);
+    let (_, lease) = server.get("/next").await;
+    let (status, advanced) = server.request("POST", "/debug/clock/advance?ms=60000", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(advanced["now"], lease["exp"]);
+    assert_eq!(server.get("/heartbeat/1").await.1["error"]["code"], json!(3));
+    // never backwards
+    let (_, set) = server.request("POST", "/debug/clock/set?ms=0", None).await;
+    assert_eq!(set["error"]["code"], json!(14));
+    assert_eq!(server.get("/stats").await.1["expirations"], json!(1));
+}